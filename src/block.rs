@@ -0,0 +1,361 @@
+//! Data block of a single-file table.
+//!
+//! ```text
+//! entry:   shared_len | unshared_len | value_len | key suffix | value
+//! trailer: restart offset (u32) * num_restarts | num_restarts (u32)
+//! ```
+//!
+//! Lengths are varints. Each entry stores only the part of its key that differs
+//! from the previous key; every `RESTART_INTERVAL` entries the full key is
+//! stored and its offset recorded as a restart point, so a lookup can binary
+//! search the restart points and scan at most one interval.
+
+use std::cmp::Ordering;
+use std::io;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+
+use crate::{ByteStr, ByteString, KeyValuePair};
+
+const RESTART_INTERVAL: usize = 16;
+const U32_SIZE: usize = 4;
+
+pub(crate) struct BlockBuilder {
+    buffer: Vec<u8>,
+    restarts: Vec<u32>,
+    counter: usize,
+    last_key: ByteString,
+}
+
+impl BlockBuilder {
+    pub(crate) fn new() -> BlockBuilder {
+        BlockBuilder {
+            buffer: Vec::new(),
+            restarts: vec![0],
+            counter: 0,
+            last_key: ByteString::new(),
+        }
+    }
+
+    /// Appends an entry; keys must be added in increasing order.
+    pub(crate) fn add(&mut self, key: &ByteStr, value: &ByteStr) {
+        debug_assert!(self.is_empty() || key > self.last_key.as_slice());
+        let shared = if self.counter < RESTART_INTERVAL {
+            shared_prefix_len(&self.last_key, key)
+        } else {
+            self.restarts.push(self.buffer.len() as u32);
+            self.counter = 0;
+            0
+        };
+        write_varint(&mut self.buffer, shared as u32);
+        write_varint(&mut self.buffer, (key.len() - shared) as u32);
+        write_varint(&mut self.buffer, value.len() as u32);
+        self.buffer.extend_from_slice(&key[shared..]);
+        self.buffer.extend_from_slice(value);
+        self.last_key.truncate(shared);
+        self.last_key.extend_from_slice(&key[shared..]);
+        self.counter += 1;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub(crate) fn estimated_size(&self) -> usize {
+        self.buffer.len() + (self.restarts.len() + 1) * U32_SIZE
+    }
+
+    /// Returns the encoded block and resets the builder for the next one.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buffer);
+        for restart in &self.restarts {
+            write_u32(&mut block, *restart);
+        }
+        write_u32(&mut block, self.restarts.len() as u32);
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+/// Decoded block. Values returned by `Block::get` share its buffer.
+pub(crate) struct Block {
+    data: Bytes,
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    pub(crate) fn new(data: Bytes) -> io::Result<Block> {
+        if data.len() < U32_SIZE {
+            return Err(corrupted("block is too small"));
+        }
+        let num_restarts = LittleEndian::read_u32(&data[data.len() - U32_SIZE..]) as usize;
+        let restarts_size = num_restarts
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(U32_SIZE))
+            .filter(|size| *size <= data.len())
+            .ok_or_else(|| corrupted("bad number of restart points"))?;
+        Ok(Block {
+            restarts_offset: data.len() - restarts_size,
+            num_restarts,
+            data,
+        })
+    }
+
+    /// Size of the decoded block.
+    pub(crate) fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn restart_point(&self, i: usize) -> usize {
+        let pos = self.restarts_offset + i * U32_SIZE;
+        LittleEndian::read_u32(&self.data[pos..pos + U32_SIZE]) as usize
+    }
+
+    /// Decodes the entry at `offset`, rebuilding its key in `key` from the
+    /// previous one. Returns the value range and the offset of the next entry.
+    fn decode_entry(
+        &self,
+        offset: usize,
+        key: &mut ByteString,
+    ) -> io::Result<(Range<usize>, usize)> {
+        let mut pos = offset;
+        let shared = read_varint(&self.data[..self.restarts_offset], &mut pos)? as usize;
+        let unshared = read_varint(&self.data[..self.restarts_offset], &mut pos)? as usize;
+        let value_len = read_varint(&self.data[..self.restarts_offset], &mut pos)? as usize;
+        let key_end = pos + unshared;
+        let value_end = key_end + value_len;
+        if shared > key.len() || value_end > self.restarts_offset {
+            return Err(corrupted("bad entry"));
+        }
+        key.truncate(shared);
+        key.extend_from_slice(&self.data[pos..key_end]);
+        Ok((key_end..value_end, value_end))
+    }
+
+    fn key_at_restart(&self, i: usize) -> io::Result<ByteString> {
+        let mut key = ByteString::new();
+        self.decode_entry(self.restart_point(i), &mut key)?;
+        Ok(key)
+    }
+
+    /// Last restart point whose key is <= `key`, or the first one.
+    fn restart_before(&self, key: &ByteStr) -> io::Result<usize> {
+        let mut left = 0;
+        let mut right = self.num_restarts;
+        while left + 1 < right {
+            let mid = (left + right) / 2;
+            match self.key_at_restart(mid)?.as_slice().cmp(key) {
+                Ordering::Greater => right = mid,
+                _ => left = mid,
+            }
+        }
+        Ok(left)
+    }
+
+    pub(crate) fn get(&self, key: &ByteStr) -> io::Result<Option<Bytes>> {
+        if self.num_restarts == 0 {
+            return Ok(None);
+        }
+        let mut offset = self.restart_point(self.restart_before(key)?);
+        let mut current = ByteString::new();
+        while offset < self.restarts_offset {
+            let (value, next) = self.decode_entry(offset, &mut current)?;
+            match current.as_slice().cmp(key) {
+                Ordering::Equal => return Ok(Some(self.data.slice(value))),
+                Ordering::Greater => return Ok(None),
+                Ordering::Less => offset = next,
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl IntoIterator for Block {
+    type Item = io::Result<KeyValuePair>;
+    type IntoIter = BlockIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        BlockIter::new(Arc::new(self))
+    }
+}
+
+/// Iterates over a block that may also sit in the block cache.
+pub(crate) struct BlockIter {
+    block: Arc<Block>,
+    offset: usize,
+    key: ByteString,
+}
+
+impl BlockIter {
+    pub(crate) fn new(block: Arc<Block>) -> BlockIter {
+        BlockIter {
+            block,
+            offset: 0,
+            key: ByteString::new(),
+        }
+    }
+
+    /// Moves to the first entry with a key not less than `key`.
+    pub(crate) fn seek(&mut self, key: &ByteStr) -> io::Result<()> {
+        let block = &self.block;
+        self.offset = block.restarts_offset;
+        if block.num_restarts == 0 {
+            return Ok(());
+        }
+        let mut offset = block.restart_point(block.restart_before(key)?);
+        let mut current = ByteString::new();
+        while offset < block.restarts_offset {
+            let (_, next) = block.decode_entry(offset, &mut current)?;
+            if current.as_slice() >= key {
+                // decoding the entry again against its own key rebuilds it
+                self.offset = offset;
+                self.key = current;
+                break;
+            }
+            offset = next;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = io::Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.block.restarts_offset {
+            return None;
+        }
+        match self.block.decode_entry(self.offset, &mut self.key) {
+            Ok((value, next)) => {
+                self.offset = next;
+                let value = self.block.data[value].to_vec();
+                Some(Ok(KeyValuePair::new(self.key.clone(), value)))
+            }
+            Err(err) => {
+                self.offset = self.block.restarts_offset;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn shared_prefix_len(a: &ByteStr, b: &ByteStr) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+fn write_u32(buf: &mut Vec<u8>, val: u32) {
+    let mut bytes = [0u8; U32_SIZE];
+    LittleEndian::write_u32(&mut bytes, val);
+    buf.extend_from_slice(&bytes);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut val: u32) {
+    while val >= 0x80 {
+        buf.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    buf.push(val as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> io::Result<u32> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| corrupted("truncated varint"))?;
+        *pos += 1;
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(corrupted("varint is too long"))
+}
+
+fn corrupted(reason: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("corrupted block: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use crate::block::{Block, BlockBuilder, BlockIter};
+
+    fn build_block(count: usize) -> Block {
+        let mut builder = BlockBuilder::new();
+        for i in 0..count {
+            let key = format!("user_key_{:05}", i).into_bytes();
+            builder.add(&key, format!("value_{}", i).as_bytes());
+        }
+        Block::new(builder.finish().into()).unwrap()
+    }
+
+    #[test]
+    fn block_get() {
+        let block = build_block(100);
+        for i in 0..100 {
+            let key = format!("user_key_{:05}", i).into_bytes();
+            assert_eq!(
+                Some(Bytes::from(format!("value_{}", i))),
+                block.get(&key).unwrap()
+            );
+        }
+        assert_eq!(None, block.get(b"user_key_").unwrap());
+        assert_eq!(None, block.get(b"user_key_00010a").unwrap());
+        assert_eq!(None, block.get(b"zzz").unwrap());
+    }
+
+    #[test]
+    fn block_iterator() {
+        let block = build_block(40);
+        for (i, kv) in block.into_iter().enumerate() {
+            let kv = kv.unwrap();
+            assert_eq!(format!("user_key_{:05}", i).as_bytes(), kv.key_ref());
+            assert_eq!(format!("value_{}", i).as_bytes(), kv.value_ref());
+        }
+    }
+
+    #[test]
+    fn block_iterator_seek() {
+        let mut iter = BlockIter::new(Arc::new(build_block(100)));
+        iter.seek(b"user_key_00042").unwrap();
+        assert_eq!(b"user_key_00042", iter.next().unwrap().unwrap().key_ref());
+        assert_eq!(b"user_key_00043", iter.next().unwrap().unwrap().key_ref());
+        // between keys
+        iter.seek(b"user_key_00017a").unwrap();
+        assert_eq!(b"user_key_00018", iter.next().unwrap().unwrap().key_ref());
+        iter.seek(b"a").unwrap();
+        assert_eq!(b"user_key_00000", iter.next().unwrap().unwrap().key_ref());
+        iter.seek(b"zzz").unwrap();
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn block_shares_key_prefixes() {
+        let mut builder = BlockBuilder::new();
+        let mut raw_size = 0;
+        for i in 0..16 {
+            let key = format!("a_long_shared_key_prefix_{:02}", i).into_bytes();
+            raw_size += key.len() + 1;
+            builder.add(&key, b"v");
+        }
+        assert!(builder.finish().len() < raw_size / 2);
+    }
+
+    #[test]
+    fn truncated_block_is_rejected() {
+        assert!(Block::new(Bytes::from_static(&[1, 0])).is_err());
+        assert!(Block::new(Bytes::from_static(&[0xff, 0xff, 0xff, 0x00])).is_err());
+    }
+}
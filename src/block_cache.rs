@@ -0,0 +1,198 @@
+//! Decoded data blocks kept in memory, shared by all tables of a store.
+//! Blocks are keyed by the number of their table and their offset in it, so
+//! they stay valid when a table moves to another level. The cache is split
+//! into shards with their own lock and LRU list; each shard holds an equal
+//! part of the capacity.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::block::Block;
+use crate::config::Config;
+
+const NUM_SHARDS: usize = 16;
+
+type BlockKey = (u128, u64);
+
+pub(crate) struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Shard {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    /// Keys by the tick they were last used at, least recently used first.
+    lru: BTreeMap<u64, BlockKey>,
+    usage: usize,
+    tick: u64,
+}
+
+struct CachedBlock {
+    block: Arc<Block>,
+    charge: usize,
+    tick: u64,
+}
+
+impl Shard {
+    fn touch(&mut self, key: BlockKey) -> Option<Arc<Block>> {
+        self.tick += 1;
+        let cached = self.blocks.get_mut(&key)?;
+        self.lru.remove(&cached.tick);
+        cached.tick = self.tick;
+        self.lru.insert(self.tick, key);
+        Some(cached.block.clone())
+    }
+
+    fn remove(&mut self, key: BlockKey) {
+        if let Some(cached) = self.blocks.remove(&key) {
+            self.lru.remove(&cached.tick);
+            self.usage -= cached.charge;
+        }
+    }
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            shards: (0..NUM_SHARDS).map(|_| Mutex::default()).collect(),
+            shard_capacity: capacity / NUM_SHARDS,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// `None` if `Config::block_cache_size_bytes` is 0.
+    pub(crate) fn from_config(config: &Config) -> Option<BlockCache> {
+        (config.block_cache_size_bytes > 0).then(|| BlockCache::new(config.block_cache_size_bytes))
+    }
+
+    fn shard(&self, key: BlockKey) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+    }
+
+    pub(crate) fn get(&self, file_number: u128, offset: u64) -> Option<Arc<Block>> {
+        let key = (file_number, offset);
+        let block = self.shard(key).lock().touch(key);
+        let counter = if block.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    /// Adds a block, evicting the least recently used ones of its shard to
+    /// make room. Blocks larger than a shard are not cached.
+    pub(crate) fn insert(&self, file_number: u128, offset: u64, block: Arc<Block>) {
+        let charge = block.size() + mem::size_of::<Block>();
+        if charge > self.shard_capacity {
+            return;
+        }
+        let key = (file_number, offset);
+        let mut shard = self.shard(key).lock();
+        shard.remove(key);
+        while shard.usage + charge > self.shard_capacity {
+            match shard.lru.first_key_value() {
+                Some((_, &oldest)) => shard.remove(oldest),
+                None => break,
+            }
+        }
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.lru.insert(tick, key);
+        shard.blocks.insert(
+            key,
+            CachedBlock {
+                block,
+                charge,
+                tick,
+            },
+        );
+        shard.usage += charge;
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Bytes taken by the cached blocks.
+    pub(crate) fn usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().usage).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::sync::Arc;
+
+    use crate::block::{Block, BlockBuilder};
+    use crate::block_cache::{BlockCache, NUM_SHARDS};
+
+    fn block(entries: usize) -> Arc<Block> {
+        let mut builder = BlockBuilder::new();
+        for i in 0..entries {
+            builder.add(format!("key{:04}", i).as_bytes(), b"value");
+        }
+        Arc::new(Block::new(builder.finish().into()).unwrap())
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = BlockCache::new(1024 * 1024);
+        assert!(cache.get(1, 0).is_none());
+        cache.insert(1, 0, block(10));
+        assert!(cache.get(1, 0).is_some());
+        assert!(cache.get(2, 0).is_none());
+        assert_eq!(1, cache.hits());
+        assert_eq!(2, cache.misses());
+        assert!(cache.usage() > 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let block = block(10);
+        // room for three blocks in every shard
+        let charge = block.size() + std::mem::size_of::<Block>();
+        let cache = BlockCache::new(NUM_SHARDS * charge * 3);
+        // find four offsets of a single shard
+        let offsets: Vec<u64> = (0..)
+            .filter(|offset| ptr::eq(cache.shard((1, *offset)), cache.shard((1, 0))))
+            .take(4)
+            .collect();
+        for offset in &offsets[..3] {
+            cache.insert(1, *offset, block.clone());
+        }
+        assert!(cache.get(1, offsets[0]).is_some());
+        cache.insert(1, offsets[3], block.clone());
+        assert!(cache.get(1, offsets[0]).is_some());
+        assert!(cache.get(1, offsets[1]).is_none());
+        assert!(cache.get(1, offsets[2]).is_some());
+        assert!(cache.get(1, offsets[3]).is_some());
+        assert_eq!(3 * charge, cache.usage());
+    }
+
+    #[test]
+    fn large_blocks_are_not_cached() {
+        let cache = BlockCache::new(NUM_SHARDS * 64);
+        cache.insert(1, 0, block(100));
+        assert!(cache.get(1, 0).is_none());
+        assert_eq!(0, cache.usage());
+    }
+}
@@ -1,9 +1,10 @@
 use std::fs::OpenOptions;
 use std::io;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use std::path::Path;
 
 use base64::encode as base64_encode;
+#[cfg(test)]
 use log::debug;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -18,16 +19,25 @@ pub(crate) struct Checksums {
 
 impl Checksums {
     fn calculate_checksum(path: &Path) -> io::Result<String> {
+        let data_file = OpenOptions::new().read(true).open(path)?;
+        Self::calculate_reader_checksum(data_file)
+    }
+
+    fn mismatch(path: &Path) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Can't load SSTable from {:?}. Checksum is not correct",
+                path
+            ),
+        )
+    }
+
+    fn calculate_reader_checksum<R: Read>(mut reader: R) -> io::Result<String> {
         let mut hasher = Sha256::new();
-        let mut data_file = OpenOptions::new()
-            .read(true)
-            .open(path)
-            .expect("Can't open file to calculate checksum");
-        // let start = SeekFrom::Start(0);
-        // data_file.seek(start)?;
         let mut buffer = [0; 1024];
         loop {
-            let count = data_file.read(&mut buffer)?;
+            let count = reader.read(&mut buffer)?;
             if count == 0 {
                 break;
             }
@@ -37,30 +47,45 @@ impl Checksums {
         Ok(base64_encode(hash))
     }
 
+    /// Checksums of a single-file table: `data_size` bytes of records at the
+    /// start of the file at `path` and the encoded index block.
+    pub(crate) fn for_table(path: &Path, data_size: u64, index: &[u8]) -> io::Result<Checksums> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(Checksums {
+            data_checksum: Self::calculate_reader_checksum(file.take(data_size))?,
+            index_checksum: Self::calculate_reader_checksum(index)?,
+        })
+    }
+
+    pub(crate) fn verify_table(&self, path: &Path, data_size: u64, index: &[u8]) -> io::Result<()> {
+        let calculated = Self::for_table(path, data_size, index)?;
+        if calculated.data_checksum != self.data_checksum
+            || calculated.index_checksum != self.index_checksum
+        {
+            return Err(Self::mismatch(path));
+        }
+        Ok(())
+    }
+
     pub(crate) fn verify(metadata: &SsTableMetadata) -> io::Result<()> {
         let calculated_data_hash = Checksums::calculate_checksum(&metadata.data_path())?;
         let calculated_index_hash = Checksums::calculate_checksum(&metadata.index_path())?;
         let checksum_file = OpenOptions::new()
             .read(true)
-            .open(&metadata.checksum_path())
-            .expect("Can't open checksum file");
+            .open(metadata.checksum_path())?;
         let checksums: Checksums =
             serde_json::from_reader(checksum_file).map_err(io::Error::from)?;
         if calculated_data_hash != checksums.data_checksum {
-            panic!(
-                "Can't load SSTable from {}. Checksum is not correct",
-                &metadata.data_filename
-            );
+            return Err(Self::mismatch(&metadata.data_path()));
         }
         if calculated_index_hash != checksums.index_checksum {
-            panic!(
-                "Can't load SSTable from {}. Checksum is not correct",
-                &metadata.index_filename
-            );
+            return Err(Self::mismatch(&metadata.index_path()));
         }
         Ok(())
     }
 
+    /// Legacy tables are no longer written; kept to build fixtures in tests.
+    #[cfg(test)]
     pub(crate) fn write_checksums(metadata: &SsTableMetadata) -> io::Result<()> {
         let data_base64_hash = Self::calculate_checksum(&metadata.data_path())?;
         let index_base64_hash = Self::calculate_checksum(&metadata.index_path())?;
@@ -72,10 +97,7 @@ impl Checksums {
             index_checksum: index_base64_hash,
             data_checksum: data_base64_hash,
         };
-        let checksum_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&metadata.checksum_path())?;
+        let checksum_file = std::fs::File::create(metadata.checksum_path())?;
         serde_json::to_writer(checksum_file, &checksums).map_err(io::Error::from)
     }
 }
@@ -0,0 +1,881 @@
+//! Compaction strategies, shared by the sync and tokio engines.
+//!
+//! A strategy looks at the properties of the tables in every level and
+//! picks the tables to merge next. Tables in a level are ordered by the
+//! newest data they hold (see `TableProperties::newest_sequence`), so a
+//! strategy may merge any run of tables that are adjacent in that order.
+//!
+//! The strategy is selected by `Config::compaction_strategy`:
+//!
+//! * `level_count` merges a whole level into the next one once it holds
+//!   `sstable_level_limit` tables.
+//! * `leveled` keeps levels 1 and deeper at a target size that grows by
+//!   `level_size_multiplier` per level, with disjoint key ranges, so a point
+//!   lookup reads at most one table per level below 0. A level over its limit
+//!   is compacted: all of level 0, or the oldest table of a deeper level,
+//!   merged with the tables of the next level that overlap it.
+//! * `universal` keeps every table in level 0 and merges runs of tables of
+//!   similar size, which rewrites data less often at the cost of reading more
+//!   tables per lookup.
+//! * `fifo` never merges. Tables stay in level 0 in creation order and the
+//!   oldest ones are deleted once the tables exceed a total size or age,
+//!   for time-series data where retention is all that is needed.
+//!
+//! Whatever the strategy, a merge of tables that overlap nothing else in the
+//! output level moves them there by renaming their files instead of
+//! rewriting them, see `Compaction::is_trivial_move`.
+
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+
+use serde_derive::Deserialize;
+
+use crate::config::Config;
+use crate::merge_iterator::{self, InternalIterator};
+use crate::sstable_format::{SplittingTableBuilder, TableOptions, TableProperties};
+use crate::{ByteStr, ByteString};
+
+/// Compaction strategy selected in `Config`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionStyle {
+    LevelCount,
+    #[default]
+    Leveled,
+    Universal,
+    Fifo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompactionKind {
+    /// Merge the inputs into new tables in `output_level`.
+    Merge,
+    /// Delete the inputs without writing anything.
+    Delete,
+    /// Move the inputs to `output_level` as they are, by renaming their
+    /// files, see `Compaction::is_trivial_move`. Moved tables skip the
+    /// compaction filter, so there are no moves while one is set.
+    Move,
+}
+
+/// What a `CompactionFilter` does with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// Deletes the entry. It is written as a tombstone, so older values of
+    /// the key in deeper levels stay hidden.
+    Remove,
+    /// Keeps the key with a new value.
+    ChangeValue(ByteString),
+}
+
+/// Called by compactions for every entry they write, to drop or rewrite
+/// entries without a separate scan. Tombstones are not passed to the filter.
+/// Set it with `Config::compaction_filter`.
+pub trait CompactionFilter: Send + Sync {
+    /// `level` is the level the compaction writes to.
+    fn filter(&self, level: u8, key: &ByteStr, value: &ByteStr) -> FilterDecision;
+}
+
+impl fmt::Debug for dyn CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+/// Returns the value a compaction writing to `level` stores for an entry.
+pub(crate) fn apply_filter<'a>(
+    filter: Option<&dyn CompactionFilter>,
+    level: u8,
+    key: &ByteStr,
+    value: &'a ByteStr,
+) -> Cow<'a, ByteStr> {
+    let filter = match filter {
+        Some(filter) if value != [0] => filter,
+        _ => return Cow::Borrowed(value),
+    };
+    match filter.filter(level, key, value) {
+        FilterDecision::Keep => Cow::Borrowed(value),
+        FilterDecision::Remove => Cow::Owned(vec![0]),
+        FilterDecision::ChangeValue(value) => Cow::Owned(value),
+    }
+}
+
+/// Keys that split a merge into at most `parts` key ranges of about the same
+/// number of blocks, given the index keys of the inputs. A range
+/// starts at its split key and ends before the next one.
+pub(crate) fn split_points<'a>(
+    keys: impl IntoIterator<Item = &'a ByteStr>,
+    parts: usize,
+) -> Vec<ByteString> {
+    let mut keys: Vec<&ByteStr> = keys.into_iter().collect();
+    keys.sort_unstable();
+    keys.dedup();
+    let parts = parts.min(keys.len()).max(1);
+    (1..parts)
+        .map(|i| keys[i * keys.len() / parts].to_vec())
+        .collect()
+}
+
+/// Writes the entries of `input` with keys before `end` to `builder`, through
+/// the compaction filter of `options`. Tombstones are dropped if
+/// `drop_tombstones`, see `Compaction::drops_tombstones`.
+pub(crate) fn write_merged(
+    input: &mut dyn InternalIterator,
+    builder: &mut SplittingTableBuilder,
+    level: u8,
+    options: &TableOptions,
+    end: Option<&ByteStr>,
+    drop_tombstones: bool,
+) -> io::Result<()> {
+    while let Some(kv) = input.next_entry()? {
+        if end.is_some_and(|end| kv.key_ref() >= end) {
+            break;
+        }
+        let value = apply_filter(
+            options.compaction_filter.as_deref(),
+            level,
+            kv.key_ref(),
+            kv.value_ref(),
+        );
+        if drop_tombstones && *value == [0] {
+            continue;
+        }
+        builder.add(kv.key_ref(), &value)?;
+    }
+    Ok(())
+}
+
+/// Tables to merge into `output_level` or to delete, taken from `level` and
+/// `output_level`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Compaction {
+    pub(crate) kind: CompactionKind,
+    pub(crate) level: usize,
+    pub(crate) output_level: usize,
+    pub(crate) inputs: Vec<u128>,
+}
+
+impl Compaction {
+    fn merge(level: usize, output_level: usize, inputs: Vec<u128>) -> Compaction {
+        Compaction {
+            kind: CompactionKind::Merge,
+            level,
+            output_level,
+            inputs,
+        }
+    }
+
+    fn delete(level: usize, inputs: Vec<u128>) -> Compaction {
+        Compaction {
+            kind: CompactionKind::Delete,
+            level,
+            output_level: level,
+            inputs,
+        }
+    }
+
+    pub(crate) fn is_input(&self, id: u128) -> bool {
+        self.inputs.contains(&id)
+    }
+
+    /// Whether the merge can be done by moving its inputs to `output_level`
+    /// unchanged: they all come from the level above it, their key ranges
+    /// are known and overlap neither each other nor any table already in
+    /// `output_level`.
+    pub(crate) fn is_trivial_move(&self, levels: &[Vec<&TableProperties>]) -> bool {
+        if self.kind != CompactionKind::Merge || self.level == self.output_level {
+            return false;
+        }
+        let inputs: Vec<&TableProperties> = levels[self.level]
+            .iter()
+            .filter(|table| self.is_input(table.id))
+            .copied()
+            .collect();
+        inputs.len() == self.inputs.len()
+            && inputs
+                .iter()
+                .all(|table| table.smallest_key.is_some() && table.largest_key.is_some())
+            && merge_iterator::key_order(&inputs).is_some()
+            && inputs.iter().all(|input| {
+                let range = KeyRange::of(&[input]);
+                levels[self.output_level]
+                    .iter()
+                    .all(|table| !range.overlaps(table))
+            })
+    }
+
+    /// Whether the merge may drop tombstones: no other table in the input
+    /// level or deeper overlaps the inputs, so no older value is left for a
+    /// tombstone to hide.
+    pub(crate) fn drops_tombstones(&self, levels: &[Vec<&TableProperties>]) -> bool {
+        let inputs: Vec<&TableProperties> = levels
+            .iter()
+            .flatten()
+            .filter(|table| self.is_input(table.id))
+            .copied()
+            .collect();
+        let range = KeyRange::of(&inputs);
+        levels[self.level..]
+            .iter()
+            .flatten()
+            .all(|table| self.is_input(table.id) || !range.overlaps(table))
+    }
+
+    /// Levels the inputs come from, the one holding older data first.
+    pub(crate) fn input_levels(&self) -> Vec<usize> {
+        if self.level == self.output_level {
+            vec![self.level]
+        } else {
+            vec![self.output_level, self.level]
+        }
+    }
+}
+
+/// Decides what to compact. `levels` lists the tables of every level, older
+/// data first within a level.
+pub(crate) trait CompactionStrategy: Send + Sync {
+    /// Returns the next compaction to run, or `None` if nothing needs one.
+    fn pick(&self, levels: &[Vec<&TableProperties>]) -> Option<Compaction>;
+
+    /// Estimate of the bytes compactions have to rewrite before no level
+    /// needs one, which stalls writes once it gets too large. Strategies
+    /// without an estimate return 0.
+    fn pending_compaction_bytes(&self, _levels: &[Vec<&TableProperties>]) -> u64 {
+        0
+    }
+
+    /// Sorted runs in level 0, which its compaction trigger and the level 0
+    /// write stalls count. Each table is a run of its own unless the
+    /// strategy merges level 0 into runs of several tables.
+    fn level0_runs(&self, level0: &[&TableProperties]) -> usize {
+        level0.len()
+    }
+}
+
+pub(crate) fn strategy_from_config(config: &Config) -> Box<dyn CompactionStrategy> {
+    match config.compaction_strategy {
+        CompactionStyle::LevelCount => Box::new(LevelCountCompaction {
+            level_file_limit: config.sstable_level_limit.max(1),
+        }),
+        CompactionStyle::Leveled => Box::new(LeveledCompaction {
+            level0_file_limit: config.sstable_level_limit.max(1),
+            level_size_base: config.level_size_base_bytes.max(1),
+            level_size_multiplier: config.level_size_multiplier.max(1),
+        }),
+        CompactionStyle::Universal => Box::new(UniversalCompaction {
+            file_limit: config.sstable_level_limit.max(2),
+            size_ratio_percent: config.universal_size_ratio_percent,
+            max_size_amplification_percent: config.universal_max_size_amplification_percent,
+        }),
+        CompactionStyle::Fifo => Box::new(FifoCompaction {
+            max_total_size: config.fifo_max_total_size_bytes,
+            max_age_ms: config.fifo_max_age_secs.map(|secs| u128::from(secs) * 1000),
+            time_window_ms: config
+                .fifo_time_window_secs
+                .map(|secs| u128::from(secs.max(1)) * 1000),
+        }),
+    }
+}
+
+fn all_tables(tables: &[&TableProperties]) -> Vec<u128> {
+    tables.iter().map(|table| table.id).collect()
+}
+
+/// Merges a whole level into the next one once it holds `level_file_limit`
+/// tables.
+struct LevelCountCompaction {
+    level_file_limit: usize,
+}
+
+impl CompactionStrategy for LevelCountCompaction {
+    fn pick(&self, levels: &[Vec<&TableProperties>]) -> Option<Compaction> {
+        let last = levels.len().saturating_sub(1);
+        let level = (0..last).find(|&level| levels[level].len() >= self.level_file_limit)?;
+        Some(Compaction::merge(
+            level,
+            level + 1,
+            all_tables(&levels[level]),
+        ))
+    }
+
+    /// Size of the levels that are due to be merged.
+    fn pending_compaction_bytes(&self, levels: &[Vec<&TableProperties>]) -> u64 {
+        let last = levels.len().saturating_sub(1);
+        levels[..last]
+            .iter()
+            .filter(|tables| tables.len() >= self.level_file_limit)
+            .flatten()
+            .map(|table| table.data_size)
+            .sum()
+    }
+}
+
+/// Key range covered by a set of tables. Unknown bounds, from tables written
+/// before the range was recorded, cover every key.
+struct KeyRange<'a> {
+    smallest: Option<&'a ByteStr>,
+    largest: Option<&'a ByteStr>,
+}
+
+impl<'a> KeyRange<'a> {
+    fn of(tables: &[&'a TableProperties]) -> KeyRange<'a> {
+        let smallest: Option<Vec<&ByteStr>> = tables
+            .iter()
+            .map(|table| table.smallest_key.as_deref())
+            .collect();
+        let largest: Option<Vec<&ByteStr>> = tables
+            .iter()
+            .map(|table| table.largest_key.as_deref())
+            .collect();
+        KeyRange {
+            smallest: smallest.and_then(|keys| keys.into_iter().min()),
+            largest: largest.and_then(|keys| keys.into_iter().max()),
+        }
+    }
+
+    fn overlaps(&self, table: &TableProperties) -> bool {
+        let starts_after = match (table.smallest_key.as_deref(), self.largest) {
+            (Some(smallest), Some(largest)) => smallest > largest,
+            _ => false,
+        };
+        let ends_before = match (table.largest_key.as_deref(), self.smallest) {
+            (Some(largest), Some(smallest)) => largest < smallest,
+            _ => false,
+        };
+        !starts_after && !ends_before
+    }
+}
+
+/// Merges the tables of `level` that overlap the key range from `start` to
+/// `end` (both inclusive, `None` for unbounded) into the next level, for
+/// manual compactions. Tables of `level` that overlap the chosen ones are
+/// added until none is left, so no older table stays behind with keys the
+/// moved tables also hold.
+pub(crate) fn pick_range<'a>(
+    levels: &[Vec<&'a TableProperties>],
+    level: usize,
+    start: Option<&'a ByteStr>,
+    end: Option<&'a ByteStr>,
+) -> Option<Compaction> {
+    let output_level = level + 1;
+    if output_level >= levels.len() {
+        return None;
+    }
+    let mut range = KeyRange {
+        smallest: start,
+        largest: end,
+    };
+    let mut inputs: Vec<&TableProperties> = Vec::new();
+    loop {
+        let overlapping: Vec<&TableProperties> = levels[level]
+            .iter()
+            .filter(|table| range.overlaps(table))
+            .copied()
+            .collect();
+        if overlapping.is_empty() || overlapping.len() == inputs.len() {
+            break;
+        }
+        inputs = overlapping;
+        range = KeyRange::of(&inputs);
+    }
+    if inputs.is_empty() {
+        return None;
+    }
+    let overlapping = levels[output_level]
+        .iter()
+        .filter(|table| range.overlaps(table));
+    let inputs = inputs
+        .iter()
+        .chain(overlapping)
+        .map(|table| table.id)
+        .collect();
+    Some(Compaction::merge(level, output_level, inputs))
+}
+
+/// Leveled compaction, see the module documentation.
+struct LeveledCompaction {
+    level0_file_limit: usize,
+    level_size_base: u64,
+    level_size_multiplier: u64,
+}
+
+impl LeveledCompaction {
+    /// Target size in bytes of `level`, which must be 1 or deeper.
+    fn target_size(&self, level: usize) -> u64 {
+        let mut size = self.level_size_base;
+        for _ in 1..level {
+            size = size.saturating_mul(self.level_size_multiplier);
+        }
+        size
+    }
+
+    /// How far over its limit a level is; it needs compaction from 1.0 up.
+    fn score(&self, level: usize, tables: &[&TableProperties]) -> f64 {
+        if level == 0 {
+            tables.len() as f64 / self.level0_file_limit as f64
+        } else {
+            let size: u64 = tables.iter().map(|table| table.data_size).sum();
+            size as f64 / self.target_size(level) as f64
+        }
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    /// Picks the level furthest over its limit. The last level is never
+    /// compacted.
+    fn pick(&self, levels: &[Vec<&TableProperties>]) -> Option<Compaction> {
+        let (level, score) = levels
+            .iter()
+            .enumerate()
+            .take(levels.len().saturating_sub(1))
+            .map(|(level, tables)| (level, self.score(level, tables)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if score < 1.0 {
+            return None;
+        }
+        let inputs: Vec<&TableProperties> = if level == 0 {
+            levels[0].clone()
+        } else {
+            levels[level]
+                .iter()
+                .min_by_key(|table| table.id)
+                .copied()
+                .into_iter()
+                .collect()
+        };
+        let range = KeyRange::of(&inputs);
+        let output_level = level + 1;
+        let overlapping = levels[output_level]
+            .iter()
+            .filter(|table| range.overlaps(table));
+        let inputs = inputs
+            .iter()
+            .chain(overlapping)
+            .map(|table| table.id)
+            .collect();
+        Some(Compaction::merge(level, output_level, inputs))
+    }
+
+    /// Level 0 once it is due, plus the bytes every deeper level is over its
+    /// target size.
+    fn pending_compaction_bytes(&self, levels: &[Vec<&TableProperties>]) -> u64 {
+        let size = |tables: &[&TableProperties]| -> u64 {
+            tables.iter().map(|table| table.data_size).sum()
+        };
+        let last = levels.len().saturating_sub(1);
+        let mut pending = 0;
+        for (level, tables) in levels[..last].iter().enumerate() {
+            if level == 0 {
+                if tables.len() >= self.level0_file_limit {
+                    pending += size(tables);
+                }
+            } else {
+                pending += size(tables).saturating_sub(self.target_size(level));
+            }
+        }
+        pending
+    }
+}
+
+/// Universal (size-tiered) compaction over the sorted runs of level 0, once
+/// there are `file_limit` of them. A sorted run is a flushed table or all
+/// tables written by one merge, which share its sequence range. In order:
+///
+/// * if the newer runs together are larger than
+///   `max_size_amplification_percent` of the oldest one, all runs are
+///   merged;
+/// * otherwise the newest span of at least two runs, each no larger than
+///   the ones before it together plus `size_ratio_percent`, is merged;
+/// * otherwise the newest `file_limit` runs are merged.
+struct UniversalCompaction {
+    file_limit: usize,
+    size_ratio_percent: u64,
+    max_size_amplification_percent: u64,
+}
+
+/// Splits level 0, oldest first, into sorted runs. Tables that predate the
+/// sequence range are runs of their own.
+fn sorted_runs<'a, 'b>(tables: &'b [&'a TableProperties]) -> Vec<&'b [&'a TableProperties]> {
+    tables
+        .chunk_by(|a, b| a.sequence_range.is_some() && a.sequence_range == b.sequence_range)
+        .collect()
+}
+
+impl CompactionStrategy for UniversalCompaction {
+    fn pick(&self, levels: &[Vec<&TableProperties>]) -> Option<Compaction> {
+        if self.level0_runs(levels.first()?) < self.file_limit {
+            return None;
+        }
+        let runs = sorted_runs(&levels[0]);
+        let compaction = |runs: &[&[&TableProperties]]| {
+            let inputs: Vec<&TableProperties> = runs.concat();
+            Compaction::merge(0, 0, all_tables(&inputs))
+        };
+        let sizes: Vec<u64> = runs
+            .iter()
+            .map(|run| run.iter().map(|table| table.data_size).sum())
+            .collect();
+        let oldest = sizes[0];
+        let newer: u64 = sizes[1..].iter().sum();
+        if newer.saturating_mul(100) >= oldest.saturating_mul(self.max_size_amplification_percent) {
+            return Some(compaction(&runs));
+        }
+        for end in (2..=runs.len()).rev() {
+            let mut size = sizes[end - 1];
+            let mut start = end - 1;
+            while start > 0 {
+                let limit = size.saturating_mul(100 + self.size_ratio_percent) / 100;
+                if sizes[start - 1] > limit {
+                    break;
+                }
+                start -= 1;
+                size += sizes[start];
+            }
+            if end - start >= 2 {
+                return Some(compaction(&runs[start..end]));
+            }
+        }
+        Some(compaction(&runs[runs.len() - self.file_limit..]))
+    }
+
+    fn level0_runs(&self, level0: &[&TableProperties]) -> usize {
+        sorted_runs(level0).len()
+    }
+}
+
+/// FIFO compaction over the tables of level 0. Once they are larger than
+/// `max_total_size` together, or the oldest one only holds data older than
+/// `max_age_ms`, the oldest table is deleted. With `time_window_ms` tables
+/// are bucketed by the window their newest data falls in and whole windows
+/// are deleted instead.
+struct FifoCompaction {
+    max_total_size: Option<u64>,
+    max_age_ms: Option<u128>,
+    time_window_ms: Option<u128>,
+}
+
+impl FifoCompaction {
+    /// Time in milliseconds the newest data of a table was written at.
+    fn written_at(table: &TableProperties) -> u128 {
+        table.newest_sequence().0
+    }
+
+    fn window(&self, table: &TableProperties) -> u128 {
+        match self.time_window_ms {
+            Some(window) => Self::written_at(table) / window,
+            None => table.id,
+        }
+    }
+}
+
+impl CompactionStrategy for FifoCompaction {
+    fn pick(&self, levels: &[Vec<&TableProperties>]) -> Option<Compaction> {
+        let tables = levels.first()?;
+        let oldest = tables.first()?;
+        let oldest_window = self.window(oldest);
+        let oldest_tables: Vec<&TableProperties> = tables
+            .iter()
+            .take_while(|table| self.window(table) == oldest_window)
+            .copied()
+            .collect();
+        let total_size: u64 = tables.iter().map(|table| table.data_size).sum();
+        let too_large = self.max_total_size.is_some_and(|max| total_size > max);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        let expired = self.max_age_ms.is_some_and(|max_age| {
+            let newest = oldest_tables
+                .iter()
+                .map(|table| Self::written_at(table))
+                .max();
+            newest.is_some_and(|written_at| now.saturating_sub(written_at) > max_age)
+        });
+        if too_large || expired {
+            Some(Compaction::delete(0, all_tables(&oldest_tables)))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::compaction::{
+        pick_range, split_points, strategy_from_config, Compaction, CompactionStyle,
+    };
+    use crate::config::Config;
+    use crate::sstable_format::TableProperties;
+    use crate::ByteStr;
+
+    fn table(id: u128, smallest: &str, largest: &str, data_size: u64) -> TableProperties {
+        TableProperties {
+            id,
+            data_size,
+            smallest_key: Some(smallest.as_bytes().to_vec()),
+            largest_key: Some(largest.as_bytes().to_vec()),
+            ..TableProperties::default()
+        }
+    }
+
+    fn config(compaction_strategy: CompactionStyle) -> Config {
+        Config {
+            sstable_level_limit: 2,
+            level_size_base_bytes: 100,
+            level_size_multiplier: 10,
+            compaction_strategy,
+            ..Config::default()
+        }
+    }
+
+    fn pick(style: CompactionStyle, levels: &[&[TableProperties]]) -> Option<Compaction> {
+        let levels: Vec<Vec<&TableProperties>> =
+            levels.iter().map(|level| level.iter().collect()).collect();
+        strategy_from_config(&config(style)).pick(&levels)
+    }
+
+    #[test]
+    fn level_count_merges_whole_level() {
+        let l0 = [table(5, "c", "f", 10)];
+        let l1 = [table(1, "a", "b", 10), table(2, "e", "g", 10)];
+        assert_eq!(
+            Some(Compaction::merge(1, 2, vec![1, 2])),
+            pick(CompactionStyle::LevelCount, &[&l0, &l1, &[]])
+        );
+    }
+
+    #[test]
+    fn leveled_merges_level0_with_overlapping_tables() {
+        let l0 = [table(5, "c", "f", 10), table(6, "a", "d", 10)];
+        let l1 = [
+            table(1, "a", "b", 10),
+            table(2, "e", "g", 10),
+            table(3, "h", "k", 10),
+        ];
+        assert_eq!(
+            Some(Compaction::merge(0, 1, vec![5, 6, 1, 2])),
+            pick(CompactionStyle::Leveled, &[&l0, &l1, &[]])
+        );
+    }
+
+    #[test]
+    fn leveled_compacts_oldest_table_of_oversized_level() {
+        let l1 = [table(4, "a", "c", 60), table(3, "d", "f", 60)];
+        let l2 = [table(1, "a", "d", 10), table(2, "g", "k", 10)];
+        assert_eq!(
+            Some(Compaction::merge(1, 2, vec![3, 1])),
+            pick(CompactionStyle::Leveled, &[&[], &l1, &l2])
+        );
+    }
+
+    #[test]
+    fn leveled_leaves_levels_within_limits_alone() {
+        let l0 = [table(5, "a", "z", 10)];
+        let l1 = [table(1, "a", "z", 90)];
+        let l2 = [table(2, "a", "z", 5000)];
+        assert_eq!(None, pick(CompactionStyle::Leveled, &[&l0, &l1, &l2]));
+    }
+
+    #[test]
+    fn leveled_pending_bytes_count_oversized_levels() {
+        let l0 = [table(5, "a", "c", 10), table(6, "d", "f", 10)];
+        let l1 = [table(3, "a", "c", 60), table(4, "d", "f", 100)];
+        let l2 = [table(1, "a", "z", 5000)];
+        let levels: Vec<Vec<&TableProperties>> = vec![
+            l0.iter().collect(),
+            l1.iter().collect(),
+            l2.iter().collect(),
+        ];
+        let strategy = strategy_from_config(&config(CompactionStyle::Leveled));
+        assert_eq!(20 + 60, strategy.pending_compaction_bytes(&levels));
+    }
+
+    #[test]
+    fn universal_merges_run_of_similar_sizes() {
+        let l0 = [
+            table(1, "a", "z", 1000),
+            table(2, "a", "z", 100),
+            table(3, "a", "z", 10),
+            table(4, "a", "z", 10),
+        ];
+        assert_eq!(
+            Some(Compaction::merge(0, 0, vec![3, 4])),
+            pick(CompactionStyle::Universal, &[&l0, &[]])
+        );
+    }
+
+    #[test]
+    fn universal_merges_everything_on_size_amplification() {
+        let l0 = [
+            table(1, "a", "z", 100),
+            table(2, "a", "z", 150),
+            table(3, "a", "z", 60),
+        ];
+        assert_eq!(
+            Some(Compaction::merge(0, 0, vec![1, 2, 3])),
+            pick(CompactionStyle::Universal, &[&l0, &[]])
+        );
+    }
+
+    #[test]
+    fn universal_counts_merge_outputs_as_one_run() {
+        let merged = |id, smallest, largest| TableProperties {
+            sequence_range: Some((1, 4)),
+            ..table(id, smallest, largest, 100)
+        };
+        let flushed = |id| TableProperties {
+            sequence_range: Some((id, id)),
+            ..table(id, "a", "z", 10)
+        };
+        // a merge split into four tables is a single run
+        let mut l0 = vec![
+            merged(5, "a", "f"),
+            merged(6, "g", "m"),
+            merged(7, "n", "t"),
+            merged(8, "u", "z"),
+        ];
+        assert_eq!(None, pick(CompactionStyle::Universal, &[&l0, &[]]));
+        l0.extend([flushed(9), flushed(10)]);
+        assert_eq!(
+            Some(Compaction::merge(0, 0, vec![9, 10])),
+            pick(CompactionStyle::Universal, &[&l0, &[]])
+        );
+    }
+
+    #[test]
+    fn universal_runs_mix_merge_outputs_and_flushes() {
+        let universal = Config {
+            sstable_level_limit: 4,
+            ..config(CompactionStyle::Universal)
+        };
+        let strategy = strategy_from_config(&universal);
+        let merged = |id, smallest, largest| TableProperties {
+            sequence_range: Some((1, 8)),
+            ..table(id, smallest, largest, 1000)
+        };
+        let mut l0: Vec<TableProperties> = ["a", "e", "i", "m", "q", "u"]
+            .iter()
+            .enumerate()
+            .map(|(i, smallest)| merged(i as u128 + 1, smallest, "z"))
+            .collect();
+        // each flush adds a run, compaction starts once there are four
+        for (id, runs) in [(9, 2), (10, 3), (11, 4)] {
+            l0.push(TableProperties {
+                sequence_range: Some((id, id)),
+                ..table(id, "a", "z", 10)
+            });
+            let levels = vec![l0.iter().collect(), vec![]];
+            assert_eq!(runs, strategy.level0_runs(&levels[0]));
+            assert_eq!(runs >= 4, strategy.pick(&levels).is_some());
+        }
+        let levels = vec![l0.iter().collect(), vec![]];
+        assert_eq!(
+            Some(Compaction::merge(0, 0, vec![9, 10, 11])),
+            strategy.pick(&levels)
+        );
+        // other strategies count every table
+        let leveled = strategy_from_config(&config(CompactionStyle::Leveled));
+        assert_eq!(9, leveled.level0_runs(&levels[0]));
+    }
+
+    #[test]
+    fn fifo_deletes_oldest_table_over_total_size() {
+        let config = Config {
+            compaction_strategy: CompactionStyle::Fifo,
+            fifo_max_total_size_bytes: Some(250),
+            ..Config::default()
+        };
+        let l0 = [
+            table(1, "a", "z", 100),
+            table(2, "a", "z", 100),
+            table(3, "a", "z", 100),
+        ];
+        let levels = vec![l0.iter().collect(), vec![]];
+        let strategy = strategy_from_config(&config);
+        assert_eq!(Some(Compaction::delete(0, vec![1])), strategy.pick(&levels));
+        let levels = vec![l0[1..].iter().collect(), vec![]];
+        assert_eq!(None, strategy.pick(&levels));
+    }
+
+    #[test]
+    fn fifo_deletes_expired_time_window() {
+        let config = Config {
+            compaction_strategy: CompactionStyle::Fifo,
+            fifo_max_age_secs: Some(3600),
+            fifo_time_window_secs: Some(3600),
+            ..Config::default()
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let hour = 3600 * 1000;
+        let window_start = (now / hour - 3) * hour;
+        let l0 = [
+            table(window_start + 10, "a", "z", 100),
+            table(window_start + 20, "a", "z", 100),
+            table(window_start + hour + 10, "a", "z", 100),
+            table(now, "a", "z", 100),
+        ];
+        let levels = vec![l0.iter().collect(), vec![]];
+        let strategy = strategy_from_config(&config);
+        assert_eq!(
+            Some(Compaction::delete(
+                0,
+                vec![window_start + 10, window_start + 20]
+            )),
+            strategy.pick(&levels)
+        );
+        let levels = vec![l0[3..].iter().collect(), vec![]];
+        assert_eq!(None, strategy.pick(&levels));
+    }
+
+    #[test]
+    fn range_compaction_picks_overlapping_tables() {
+        let l0 = [
+            table(1, "a", "c", 10),
+            table(2, "b", "e", 10),
+            table(3, "x", "z", 10),
+        ];
+        let l1 = [table(4, "a", "b", 10), table(5, "f", "g", 10)];
+        let levels: Vec<Vec<&TableProperties>> = vec![l0.iter().collect(), l1.iter().collect()];
+        assert_eq!(
+            Some(Compaction::merge(0, 1, vec![1, 2, 4])),
+            pick_range(&levels, 0, Some(b"a"), Some(b"b"))
+        );
+        assert_eq!(None, pick_range(&levels, 0, Some(b"m"), Some(b"n")));
+        assert_eq!(None, pick_range(&levels, 1, None, None));
+    }
+
+    #[test]
+    fn split_points_divide_distinct_keys() {
+        let keys: Vec<&ByteStr> = vec![b"a", b"c", b"b", b"c", b"d", b"e", b"f"];
+        assert_eq!(
+            vec![b"c".to_vec(), b"e".to_vec()],
+            split_points(keys.clone(), 3)
+        );
+        assert_eq!(5, split_points(keys.clone(), 10).len());
+        assert!(split_points(keys, 1).is_empty());
+        assert!(split_points(Vec::<&ByteStr>::new(), 4).is_empty());
+    }
+
+    #[test]
+    fn trivial_move_needs_disjoint_inputs() {
+        let l0 = [
+            table(1, "a", "c", 10),
+            table(2, "d", "e", 10),
+            table(3, "e", "z", 10),
+        ];
+        let l1 = [table(4, "m", "n", 10)];
+        let levels: Vec<Vec<&TableProperties>> = vec![l0.iter().collect(), l1.iter().collect()];
+        assert!(Compaction::merge(0, 1, vec![1, 2]).is_trivial_move(&levels));
+        assert!(!Compaction::merge(0, 1, vec![2, 3]).is_trivial_move(&levels));
+        assert!(!Compaction::merge(0, 1, vec![3]).is_trivial_move(&levels));
+        assert!(!Compaction::merge(0, 1, vec![1, 4]).is_trivial_move(&levels));
+        assert!(!Compaction::merge(0, 0, vec![1]).is_trivial_move(&levels));
+    }
+}
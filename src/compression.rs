@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde_derive::Deserialize;
+
+use crate::config::Config;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codec used for a data block, stored in the block trailer.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionType {
+    None = 0,
+    Lz4 = 1,
+    Snappy = 2,
+    Zstd = 3,
+}
+
+impl CompressionType {
+    pub(crate) fn from_u8(value: u8) -> io::Result<CompressionType> {
+        match value {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4),
+            2 => Ok(CompressionType::Snappy),
+            3 => Ok(CompressionType::Zstd),
+            codec => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown compression type {}", codec),
+            )),
+        }
+    }
+}
+
+/// Compression settings derived from `Config`.
+#[derive(Clone, Default)]
+pub(crate) struct CompressionOptions {
+    per_level: Vec<CompressionType>,
+    zstd_dictionary: Option<Arc<Vec<u8>>>,
+}
+
+impl CompressionOptions {
+    pub(crate) fn from_config(config: &Config) -> io::Result<CompressionOptions> {
+        let zstd_dictionary = match &config.zstd_dictionary_path {
+            Some(path) => Some(Arc::new(fs::read(path)?)),
+            None => None,
+        };
+        Ok(CompressionOptions {
+            per_level: config.compression_per_level.clone(),
+            zstd_dictionary,
+        })
+    }
+
+    /// Levels past the end of `compression_per_level` use its last entry.
+    pub(crate) fn for_level(&self, level: u8) -> CompressionType {
+        self.per_level
+            .get(level as usize)
+            .or_else(|| self.per_level.last())
+            .copied()
+            .unwrap_or(CompressionType::None)
+    }
+
+    pub(crate) fn zstd_dictionary(&self) -> Option<&Arc<Vec<u8>>> {
+        self.zstd_dictionary.as_ref()
+    }
+}
+
+/// Compresses `raw` with `codec`. Falls back to storing the block as is when
+/// compression saves less than 1/8 of its size.
+pub(crate) fn compress(
+    codec: CompressionType,
+    raw: Vec<u8>,
+    dictionary: Option<&[u8]>,
+) -> io::Result<(CompressionType, Vec<u8>)> {
+    let compressed = match codec {
+        CompressionType::None => return Ok((CompressionType::None, raw)),
+        CompressionType::Lz4 => lz4_flex::compress_prepend_size(&raw),
+        CompressionType::Snappy => snap::raw::Encoder::new()
+            .compress_vec(&raw)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+        CompressionType::Zstd => match dictionary {
+            Some(dictionary) => {
+                zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dictionary)?.compress(&raw)?
+            }
+            None => zstd::bulk::compress(&raw, ZSTD_LEVEL)?,
+        },
+    };
+    if compressed.len() < raw.len() - raw.len() / 8 {
+        Ok((codec, compressed))
+    } else {
+        Ok((CompressionType::None, raw))
+    }
+}
+
+pub(crate) fn decompress(
+    codec: CompressionType,
+    data: Bytes,
+    dictionary: Option<&[u8]>,
+) -> io::Result<Bytes> {
+    let raw = match codec {
+        CompressionType::None => return Ok(data),
+        CompressionType::Lz4 => lz4_flex::decompress_size_prepended(&data)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+        CompressionType::Snappy => snap::raw::Decoder::new()
+            .decompress_vec(&data)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+        CompressionType::Zstd => {
+            let mut raw = Vec::new();
+            match dictionary {
+                Some(dictionary) => {
+                    zstd::stream::read::Decoder::with_dictionary(data.as_ref(), dictionary)?
+                        .read_to_end(&mut raw)?;
+                }
+                None => {
+                    zstd::stream::read::Decoder::new(data.as_ref())?.read_to_end(&mut raw)?;
+                }
+            }
+            raw
+        }
+    };
+    Ok(Bytes::from(raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{compress, decompress, CompressionType};
+
+    fn json_block() -> Vec<u8> {
+        let mut raw = Vec::new();
+        for i in 0..100 {
+            raw.extend_from_slice(
+                format!("{{\"id\":{},\"name\":\"user\",\"active\":true}}", i).as_bytes(),
+            );
+        }
+        raw
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        for codec in [
+            CompressionType::Lz4,
+            CompressionType::Snappy,
+            CompressionType::Zstd,
+        ] {
+            let raw = json_block();
+            let (used, compressed) = compress(codec, raw.clone(), None).unwrap();
+            assert_eq!(codec, used);
+            assert!(compressed.len() < raw.len());
+            assert_eq!(raw, decompress(used, compressed.into(), None).unwrap());
+        }
+    }
+
+    #[test]
+    fn zstd_with_dictionary_round_trip() {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("{{\"id\":{},\"name\":\"user_{}\"}}", i, i * 7).into_bytes())
+            .collect();
+        let dictionary = zstd::dict::from_samples(&samples, 1024).unwrap();
+        let raw = json_block();
+        let (used, compressed) =
+            compress(CompressionType::Zstd, raw.clone(), Some(&dictionary)).unwrap();
+        assert_eq!(CompressionType::Zstd, used);
+        assert_eq!(
+            raw,
+            decompress(used, compressed.into(), Some(&dictionary)).unwrap()
+        );
+    }
+
+    #[test]
+    fn incompressible_block_is_stored_raw() {
+        let raw: Vec<u8> = (0..=255).collect();
+        let (used, stored) = compress(CompressionType::Lz4, raw.clone(), None).unwrap();
+        assert_eq!(CompressionType::None, used);
+        assert_eq!(raw, stored);
+    }
+}
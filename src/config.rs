@@ -1,12 +1,186 @@
+use std::sync::Arc;
+
 use config::{Config as Conf, ConfigError, File};
 use serde_derive::Deserialize;
 
+use crate::compaction::{CompactionFilter, CompactionStyle};
+use crate::compression::CompressionType;
+use crate::memtable::MemTableType;
+use crate::tokio::write_buffer_manager::WriteBufferManager;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub base_path: String,
+    /// Memory a memtable's entries may take before it is flushed, allocation
+    /// overhead included. The fixed memory of an empty memtable doesn't
+    /// count.
     pub memtable_limit_bytes: usize,
     pub sstable_level_limit: usize,
+    #[serde(default)]
+    pub memtable_type: MemTableType,
+    #[serde(default)]
+    pub compaction_strategy: CompactionStyle,
+    /// Target size of level 1. Each deeper level may hold
+    /// `level_size_multiplier` times more than the one above it.
+    #[serde(default = "default_level_size_base_bytes")]
+    pub level_size_base_bytes: u64,
+    #[serde(default = "default_level_size_multiplier")]
+    pub level_size_multiplier: u64,
+    /// Compactions start a new output table once this many bytes are written.
+    #[serde(default = "default_target_file_size_bytes")]
+    pub target_file_size_bytes: u64,
+    /// How much larger than the newer tables before it a table may be and
+    /// still join their merge under universal compaction.
+    #[serde(default = "default_universal_size_ratio_percent")]
+    pub universal_size_ratio_percent: u64,
+    /// Universal compaction merges all tables once the newer ones together
+    /// exceed this share of the oldest one.
+    #[serde(default = "default_universal_max_size_amplification_percent")]
+    pub universal_max_size_amplification_percent: u64,
+    /// FIFO compaction deletes the oldest tables while all tables together
+    /// are larger than this.
+    #[serde(default)]
+    pub fifo_max_total_size_bytes: Option<u64>,
+    /// FIFO compaction deletes tables whose newest data is older than this.
+    #[serde(default)]
+    pub fifo_max_age_secs: Option<u64>,
+    /// Buckets tables into time windows of this length, so FIFO compaction
+    /// deletes a whole window at a time.
+    #[serde(default)]
+    pub fifo_time_window_secs: Option<u64>,
+    /// Threads a merge is split across, each compacting its own key range
+    /// into its own tables. Only `Db` uses it; `LsmStorage` compacts on the
+    /// calling thread.
+    #[serde(default = "default_compaction_threads")]
+    pub compaction_threads: usize,
+    /// Limits the bytes flushes and compactions write per second. Unlimited
+    /// if not set.
+    #[serde(default)]
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// Treats `rate_limit_bytes_per_sec` as an upper bound and lowers the
+    /// limit while compactions don't need all of it.
+    #[serde(default)]
+    pub rate_limit_auto_tune: bool,
+    /// `Db` delays writes once level 0 holds this many sorted runs, and
+    /// stops them at `level0_stop_writes_trigger`, until compaction catches
+    /// up. Each table is a run, except under universal compaction, where the
+    /// tables of one merge are a single run. Neither trigger goes below
+    /// `sstable_level_limit`; 0 disables the trigger.
+    #[serde(default = "default_level0_slowdown_writes_trigger")]
+    pub level0_slowdown_writes_trigger: usize,
+    #[serde(default = "default_level0_stop_writes_trigger")]
+    pub level0_stop_writes_trigger: usize,
+    /// `Db` delays writes once the compaction strategy estimates this many
+    /// bytes need compaction, and stops them at the hard limit. 0 disables
+    /// the limit.
+    #[serde(default = "default_soft_pending_compaction_bytes_limit")]
+    pub soft_pending_compaction_bytes_limit: u64,
+    #[serde(default = "default_hard_pending_compaction_bytes_limit")]
+    pub hard_pending_compaction_bytes_limit: u64,
+    /// Memtables `Db` keeps in memory, the active one included. Writes stop
+    /// while the active memtable is full and the others still wait to be
+    /// flushed; with more than 3 they are delayed when one slot is left.
+    #[serde(default = "default_max_write_buffer_number")]
+    pub max_write_buffer_number: usize,
+    /// Full memtables `Db` waits for before flushing, merged into a single
+    /// level 0 table. Capped at `max_write_buffer_number - 1`.
+    #[serde(default = "default_min_write_buffer_number_to_merge")]
+    pub min_write_buffer_number_to_merge: usize,
+    /// Memory budget for all memtables of a `Db`, 0 for none. Ignored if
+    /// `write_buffer_manager` is set.
+    #[serde(default)]
+    pub db_write_buffer_size: usize,
+    /// Budget shared with other `Db`s. Set in code, it can't come from the
+    /// config file.
+    #[serde(skip)]
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    /// Rate delayed writes are held to.
+    #[serde(default = "default_delayed_write_rate_bytes_per_sec")]
+    pub delayed_write_rate_bytes_per_sec: u64,
+    /// Set in code, it can't come from the config file.
+    #[serde(skip)]
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Codec for data blocks written to each level; levels past the end of
+    /// the list use its last entry. Empty means no compression.
+    #[serde(default)]
+    pub compression_per_level: Vec<CompressionType>,
+    /// Dictionary file used for `zstd` blocks. It is embedded in every table
+    /// written with it, so it can change without breaking old tables.
+    #[serde(default)]
+    pub zstd_dictionary_path: Option<String>,
+    /// Memory for data blocks read by lookups, shared by all tables. 0
+    /// disables the cache.
+    #[serde(default = "default_block_cache_size_bytes")]
+    pub block_cache_size_bytes: usize,
+    /// Table files `Db` keeps open. Files are opened when read and the
+    /// least recently used are closed to stay within the limit.
+    #[serde(default = "default_max_open_files")]
+    pub max_open_files: usize,
+    /// `Db` maps table files into memory and reads from the map instead of
+    /// calling into the OS. Suits data sets that fit in RAM.
+    #[serde(default)]
+    pub mmap_reads: bool,
+}
+fn default_level_size_base_bytes() -> u64 {
+    256 * 1024
+}
+
+fn default_level_size_multiplier() -> u64 {
+    10
+}
+
+fn default_target_file_size_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_compaction_threads() -> usize {
+    1
 }
+
+fn default_level0_slowdown_writes_trigger() -> usize {
+    20
+}
+
+fn default_level0_stop_writes_trigger() -> usize {
+    36
+}
+
+fn default_soft_pending_compaction_bytes_limit() -> u64 {
+    64 * 1024 * 1024 * 1024
+}
+
+fn default_hard_pending_compaction_bytes_limit() -> u64 {
+    256 * 1024 * 1024 * 1024
+}
+
+fn default_max_write_buffer_number() -> usize {
+    2
+}
+
+fn default_min_write_buffer_number_to_merge() -> usize {
+    1
+}
+
+fn default_delayed_write_rate_bytes_per_sec() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_block_cache_size_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_max_open_files() -> usize {
+    512
+}
+
+fn default_universal_size_ratio_percent() -> u64 {
+    1
+}
+
+fn default_universal_max_size_amplification_percent() -> u64 {
+    200
+}
+
 impl Config {
     pub fn new() -> Result<Self, ConfigError> {
         let mut s = Conf::default();
@@ -14,3 +188,42 @@ impl Config {
         s.try_into()
     }
 }
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            base_path: "./data".to_string(),
+            memtable_limit_bytes: 4096,
+            sstable_level_limit: 4,
+            memtable_type: MemTableType::default(),
+            compaction_strategy: CompactionStyle::default(),
+            level_size_base_bytes: default_level_size_base_bytes(),
+            level_size_multiplier: default_level_size_multiplier(),
+            target_file_size_bytes: default_target_file_size_bytes(),
+            universal_size_ratio_percent: default_universal_size_ratio_percent(),
+            universal_max_size_amplification_percent:
+                default_universal_max_size_amplification_percent(),
+            fifo_max_total_size_bytes: None,
+            fifo_max_age_secs: None,
+            fifo_time_window_secs: None,
+            compaction_threads: default_compaction_threads(),
+            rate_limit_bytes_per_sec: None,
+            rate_limit_auto_tune: false,
+            level0_slowdown_writes_trigger: default_level0_slowdown_writes_trigger(),
+            level0_stop_writes_trigger: default_level0_stop_writes_trigger(),
+            soft_pending_compaction_bytes_limit: default_soft_pending_compaction_bytes_limit(),
+            hard_pending_compaction_bytes_limit: default_hard_pending_compaction_bytes_limit(),
+            max_write_buffer_number: default_max_write_buffer_number(),
+            min_write_buffer_number_to_merge: default_min_write_buffer_number_to_merge(),
+            db_write_buffer_size: 0,
+            write_buffer_manager: None,
+            delayed_write_rate_bytes_per_sec: default_delayed_write_rate_bytes_per_sec(),
+            compaction_filter: None,
+            compression_per_level: Vec::new(),
+            zstd_dictionary_path: None,
+            block_cache_size_bytes: default_block_cache_size_bytes(),
+            max_open_files: default_max_open_files(),
+            mmap_reads: false,
+        }
+    }
+}
@@ -1,29 +1,65 @@
+use crate::fs_utils;
+use crate::rate_limiter::{IoPriority, RateLimiter};
 use crate::{ByteStr, KeyValuePair};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(test)]
+use byteorder::WriteBytesExt;
+use byteorder::{ByteOrder, LittleEndian};
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::io::{BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use memmap2::Mmap;
 
 pub(crate) struct WriteableDataFile {
-    data: File,
+    data: BufWriter<LimitedFile>,
+    path: PathBuf,
+}
+
+/// Writes that reach the file wait for their `RateLimiter`, if any.
+struct LimitedFile {
+    file: File,
+    limiter: Option<(Arc<RateLimiter>, IoPriority)>,
 }
 
 pub(crate) struct ReadOnlyDataFile {
-    data: File,
+    data: ReadSource,
+}
+
+enum ReadSource {
+    File(File),
+    /// The whole file mapped into memory. Blocks read from it are slices
+    /// of the map, which stays mapped while any of them is alive.
+    Mmap(Bytes),
 }
 
 impl WriteableDataFile {
-    pub(crate) fn open(path: &Path) -> io::Result<WriteableDataFile> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path)?;
-        Ok(WriteableDataFile { data: file })
+    /// Creates the file under a temporary name; it only shows up at `path`
+    /// once [`WriteableDataFile::commit`] succeeds. Writes go through
+    /// `limiter`, if any, at the given priority.
+    pub(crate) fn create(
+        path: &Path,
+        limiter: Option<(Arc<RateLimiter>, IoPriority)>,
+    ) -> io::Result<WriteableDataFile> {
+        let file = fs_utils::create_temp(path)?;
+        Ok(WriteableDataFile {
+            data: BufWriter::new(LimitedFile { file, limiter }),
+            path: path.to_path_buf(),
+        })
     }
 
+    pub(crate) fn commit(self) -> io::Result<()> {
+        let file = self.data.into_inner().map_err(|e| e.into_error())?;
+        fs_utils::commit(file.file, &self.path)
+    }
+
+    /// Record encoding of legacy data files; new tables are block-based, so
+    /// only test fixtures still write it.
+    #[cfg(test)]
     pub(crate) fn write_key_value(&mut self, key: &ByteStr, val: &ByteStr) -> io::Result<u64> {
         let key_len = key.len() as u32;
         let val_len = val.len() as u32;
@@ -51,13 +87,51 @@ impl Seek for WriteableDataFile {
     }
 }
 
+impl Write for LimitedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some((limiter, priority)) = &self.limiter {
+            limiter.request(buf.len(), *priority);
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for LimitedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
 impl ReadOnlyDataFile {
     pub(crate) fn open(path: &Path) -> io::Result<ReadOnlyDataFile> {
         let file = OpenOptions::new().read(true).open(path)?;
-        Ok(ReadOnlyDataFile { data: file })
+        Ok(ReadOnlyDataFile::from(file))
+    }
+
+    /// Opens the file and maps it into memory, so reads are copies from the
+    /// map instead of system calls.
+    pub(crate) fn open_mmap(path: &Path) -> io::Result<ReadOnlyDataFile> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        // SAFETY: table files are written under a temporary name and never
+        // modified once published, so the mapped bytes don't change.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(ReadOnlyDataFile {
+            data: ReadSource::Mmap(Bytes::from_owner(map)),
+        })
+    }
+
+    pub(crate) fn size(&self) -> io::Result<u64> {
+        match &self.data {
+            ReadSource::File(file) => Ok(file.metadata()?.len()),
+            ReadSource::Mmap(map) => Ok(map.len() as u64),
+        }
     }
 
-    pub(crate) fn read_record(&mut self, pos: u64) -> io::Result<Option<(KeyValuePair, u64)>> {
+    pub(crate) fn read_record(&self, pos: u64) -> io::Result<Option<(KeyValuePair, u64)>> {
         match self.read_record_unsafe(pos) {
             Ok(res) => Ok(Some(res)),
             Err(err) => match err.kind() {
@@ -67,23 +141,34 @@ impl ReadOnlyDataFile {
         }
     }
 
-    fn read_record_unsafe(&mut self, pos: u64) -> io::Result<(KeyValuePair, u64)> {
-        let seek_from = SeekFrom::Start(pos);
-        self.data.seek(seek_from)?;
-        let key_len = self.data.read_u32::<LittleEndian>()?;
-        let val_len = self.data.read_u32::<LittleEndian>()?;
+    fn read_record_unsafe(&self, pos: u64) -> io::Result<(KeyValuePair, u64)> {
+        let mut lens = [0u8; 8];
+        self.read_exact_at(&mut lens, pos)?;
+        let key_len = LittleEndian::read_u32(&lens[..4]);
+        let val_len = LittleEndian::read_u32(&lens[4..]);
         let mut key: Vec<u8> = vec![0u8; key_len as usize];
         let mut val: Vec<u8> = vec![0u8; val_len as usize];
-        self.data.read_exact(&mut key)?;
-        self.data.read_exact(&mut val)?;
+        self.read_exact_at(&mut key, pos + 8)?;
+        self.read_exact_at(&mut val, pos + 8 + u64::from(key_len))?;
         Ok((
             KeyValuePair::new(key, val),
             u64::from(8 + key_len + val_len),
         ))
     }
 
+    pub(crate) fn read_block(&self, offset: u64, size: u64) -> io::Result<Bytes> {
+        match &self.data {
+            ReadSource::File(file) => {
+                let mut block = vec![0u8; size as usize];
+                read_exact_at(file, &mut block, offset)?;
+                Ok(Bytes::from(block))
+            }
+            ReadSource::Mmap(map) => Ok(map.slice(map_range(map, offset, size as usize)?)),
+        }
+    }
+
     pub(crate) fn scan_range(
-        &mut self,
+        &self,
         key: &ByteStr,
         start: u64,
         end: u64,
@@ -101,17 +186,57 @@ impl ReadOnlyDataFile {
         }
         Ok(None)
     }
+
+    /// Reads at `offset` without moving the file position, so any number
+    /// of threads can read through one handle.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match &self.data {
+            ReadSource::File(file) => read_exact_at(file, buf, offset),
+            ReadSource::Mmap(map) => {
+                buf.copy_from_slice(&map[map_range(map, offset, buf.len())?]);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Range of `len` bytes at `offset` of a mapped file.
+fn map_range(map: &Bytes, offset: u64, len: usize) -> io::Result<Range<usize>> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|start| Some(start..start.checked_add(len)?))
+        .filter(|range| range.end <= map.len())
+        .ok_or_else(|| ErrorKind::UnexpectedEof.into())
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
 }
 
-impl Read for ReadOnlyDataFile {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.data.read(buf)
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
     }
+    Ok(())
 }
 
-impl Seek for ReadOnlyDataFile {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.data.seek(pos)
+impl From<File> for ReadOnlyDataFile {
+    fn from(file: File) -> ReadOnlyDataFile {
+        ReadOnlyDataFile {
+            data: ReadSource::File(file),
+        }
     }
 }
 
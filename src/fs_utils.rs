@@ -0,0 +1,64 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+pub(crate) fn is_temp_file(name: &str) -> bool {
+    name.ends_with(TEMP_SUFFIX)
+}
+
+/// Opens a fresh temporary file for `path`. Nothing is visible under `path`
+/// until the file is passed to [`commit`].
+pub(crate) fn create_temp(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(temp_path(path))
+}
+
+/// Fsyncs the temporary file, renames it to `path` and fsyncs the parent
+/// directory, so after a crash `path` is either absent or complete.
+pub(crate) fn commit(file: File, path: &Path) -> io::Result<()> {
+    file.sync_all()?;
+    drop(file);
+    fs::rename(temp_path(path), path)?;
+    sync_parent_dir(path)
+}
+
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn remove_temp_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if is_temp_file(name) {
+                info!("Removing orphaned temporary file {:?}", entry.path());
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
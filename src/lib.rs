@@ -3,20 +3,37 @@ extern crate config as config_lib;
 extern crate crc;
 extern crate serde_derive;
 
-pub use crate::tokio::db::Db;
+pub use crate::tokio::db::{Db, DbStats};
+pub use crate::tokio::write_buffer_manager::WriteBufferManager;
+pub use crate::tokio::write_controller::{StallReason, WriteStall};
+pub use bytes::Bytes;
 pub use sync::lsm_storage::LsmStorage;
 
+pub use crate::compaction::{CompactionFilter, CompactionStyle, FilterDecision};
+pub use crate::compression::CompressionType;
 pub use crate::kv::ByteStr;
 pub use crate::kv::ByteString;
 pub use crate::kv::KeyValuePair;
+pub use crate::memtable::MemTableType;
+pub use crate::sstable_format::TableProperties;
+mod block;
+mod block_cache;
 mod checksums;
+mod compaction;
+mod compression;
 pub mod config;
 mod datafile;
+mod fs_utils;
 mod kv;
 mod memtable;
+mod merge_iterator;
+mod rate_limiter;
+mod skiplist;
 mod sstable_bloom_filter;
+mod sstable_format;
 mod sstable_index;
 mod sstable_metadata;
 mod sync;
+mod table_cache;
 mod tokio;
 mod wal;
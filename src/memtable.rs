@@ -1,14 +1,57 @@
-use crate::ByteStr;
-use std::collections::{btree_map, BTreeMap};
+use std::collections::{BTreeMap, Bound};
+use std::io;
 use std::io::{Read, Write};
+use std::mem;
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+use serde_derive::Deserialize;
 
+use crate::merge_iterator::InternalIterator;
+use crate::skiplist::{SkipList, SkipListIter};
 use crate::wal::{CommandLog, LogRecord, WalError};
+use crate::ByteStr;
+use crate::KeyValuePair;
 
 pub type ByteString = Vec<u8>;
 
+/// Estimated `BTreeMap` node memory per entry: nodes hold up to 11 keys and
+/// values and are about two thirds full on average.
+const BTREE_ENTRY_OVERHEAD: usize = 3 * mem::size_of::<ByteString>() + 8;
+
+/// Memtable representation selected in `Config`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MemTableType {
+    /// Concurrent skiplist: writers don't block each other and readers
+    /// never wait.
+    #[default]
+    SkipList,
+    /// `BTreeMap` behind a lock, kept to compare against.
+    BTree,
+}
+
+/// Ordered in-memory storage behind a `MemTable`. Every method takes
+/// `&self`; each representation deals with concurrent access itself.
+pub(crate) trait MemTableRep: Send + Sync {
+    fn get(&self, key: &ByteStr) -> Option<Bytes>;
+    fn insert(&self, key: ByteString, val: ByteString);
+    fn remove(&self, key: &ByteStr);
+    /// Number of keys.
+    fn size(&self) -> usize;
+    /// Length of the keys and values.
+    fn size_in_bytes(&self) -> usize;
+    /// Memory the representation holds, allocation slack and bookkeeping
+    /// included.
+    fn memory_usage(&self) -> usize;
+    /// Iterates over the entries with keys from `start` on.
+    fn iter_from<'a>(&'a self, start: Option<&ByteStr>) -> Box<dyn InternalIterator + 'a>;
+}
+
 pub struct MemTable {
-    data: BTreeMap<ByteString, ByteString>,
-    bytes: usize,
+    rep: Box<dyn MemTableRep>,
+    /// `memory_usage` of the empty memtable.
+    empty_memory: usize,
 }
 
 impl Default for MemTable {
@@ -18,96 +61,240 @@ impl Default for MemTable {
 }
 impl MemTable {
     pub fn new() -> MemTable {
-        MemTable {
-            data: BTreeMap::new(),
-            // wal: log,
-            bytes: 0,
-        }
+        MemTable::with_type(MemTableType::default())
     }
 
-    pub fn from_log<T: Read + Write>(log: &mut CommandLog<T>) -> Result<MemTable, WalError> {
-        let mut data = BTreeMap::new();
-        let mut size: usize = 0;
+    pub fn with_type(memtable_type: MemTableType) -> MemTable {
+        let rep: Box<dyn MemTableRep> = match memtable_type {
+            MemTableType::SkipList => Box::new(SkipList::new()),
+            MemTableType::BTree => Box::<BTreeRep>::default(),
+        };
+        let empty_memory = rep.memory_usage();
+        MemTable { rep, empty_memory }
+    }
+
+    pub fn from_log<T: Read + Write>(
+        log: &mut CommandLog<T>,
+        memtable_type: MemTableType,
+    ) -> Result<MemTable, WalError> {
+        let memtable = MemTable::with_type(memtable_type);
         for res in log {
-            let record = res?;
-            match record {
-                LogRecord::Insert(key, val) => {
-                    let key_len = key.len();
-                    let val_len = val.len();
-                    data.insert(key, val);
-                    size += key_len + val_len;
-                }
-                LogRecord::Remove(key) => {
-                    let val = data.remove(&key);
-                    size -= val.as_ref().map(|v| v.len() + key.len()).unwrap_or(0)
-                }
+            match res? {
+                LogRecord::Insert(key, val) => memtable.insert(key, val),
+                // deletes are tombstones in the memtable, which hide older
+                // values in the tables
+                LogRecord::Remove(key) => memtable.insert(key, vec![0]),
             }
         }
-        Ok(MemTable { data, bytes: size })
+        Ok(memtable)
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Option<ByteString> {
+        self.get_pinned(key).map(|val| val.to_vec())
+    }
+
+    /// Like `get`, but the value shares the memtable's copy.
+    pub fn get_pinned(&self, key: &ByteStr) -> Option<Bytes> {
+        self.rep.get(key)
+    }
+
+    pub fn insert(&self, key: ByteString, val: ByteString) {
+        self.rep.insert(key, val)
+    }
+
+    pub fn remove(&self, key: &ByteStr) {
+        self.rep.remove(key)
+    }
+
+    pub fn size(&self) -> usize {
+        self.rep.size()
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.rep.size_in_bytes()
+    }
+
+    /// Memory the memtable holds.
+    pub fn memory_usage(&self) -> usize {
+        self.rep.memory_usage()
+    }
+
+    /// Memory the memtable holds beyond what it took when empty. Flushes go
+    /// by this, so the fixed overhead of a representation doesn't count
+    /// against `Config::memtable_limit_bytes`.
+    pub fn entries_memory_usage(&self) -> usize {
+        self.memory_usage() - self.empty_memory
+    }
+
+    /// Iterates over the entries with keys from `start` on.
+    pub(crate) fn iter_from(&self, start: Option<&ByteStr>) -> MemTableIter<'_> {
+        MemTableIter {
+            inner: self.rep.iter_from(start),
+        }
     }
 }
 
-impl IntoIterator for MemTable {
+impl<'a> IntoIterator for &'a MemTable {
     type Item = (ByteString, ByteString);
-    type IntoIter = btree_map::IntoIter<ByteString, ByteString>;
+    type IntoIter = MemTableIter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.data.into_iter()
+        self.iter_from(None)
     }
 }
-impl<'a> IntoIterator for &'a MemTable {
-    type Item = (&'a ByteString, &'a ByteString);
-    type IntoIter = btree_map::Iter<'a, ByteString, ByteString>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.data.iter()
+pub struct MemTableIter<'a> {
+    inner: Box<dyn InternalIterator + 'a>,
+}
+
+impl InternalIterator for MemTableIter<'_> {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        self.inner.next_entry()
     }
 }
 
-impl MemTable {
-    pub fn get(&self, key: &ByteStr) -> Option<&ByteString> {
-        self.data.get(key)
+impl Iterator for MemTableIter<'_> {
+    type Item = (ByteString, ByteString);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // memtables are in memory, reading them can't fail
+        let kv = self.inner.next_entry().ok()??;
+        Some((kv.key_cloned(), kv.value_owned()))
+    }
+}
+
+impl MemTableRep for SkipList {
+    fn get(&self, key: &ByteStr) -> Option<Bytes> {
+        SkipList::get(self, key).cloned()
+    }
+
+    fn insert(&self, key: ByteString, val: ByteString) {
+        SkipList::insert(self, key, val)
     }
 
-    pub fn insert(&mut self, key: ByteString, val: ByteString) -> Option<ByteString> {
+    fn remove(&self, key: &ByteStr) {
+        SkipList::remove(self, key)
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        SkipList::size_in_bytes(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        SkipList::memory_usage(self)
+    }
+
+    fn iter_from<'a>(&'a self, start: Option<&ByteStr>) -> Box<dyn InternalIterator + 'a> {
+        Box::<SkipListIter<'a>>::new(SkipList::iter_from(self, start))
+    }
+}
+
+#[derive(Default)]
+struct BTreeRep {
+    data: RwLock<BTreeData>,
+}
+
+#[derive(Default)]
+struct BTreeData {
+    /// Values with the capacity of the vector they were inserted as.
+    map: BTreeMap<ByteString, (Bytes, usize)>,
+    bytes: usize,
+    /// Capacity of the keys and values plus `BTREE_ENTRY_OVERHEAD` per
+    /// entry.
+    memory: usize,
+}
+
+impl MemTableRep for BTreeRep {
+    fn get(&self, key: &ByteStr) -> Option<Bytes> {
+        self.data.read().map.get(key).map(|(val, _)| val.clone())
+    }
+
+    fn insert(&self, key: ByteString, val: ByteString) {
+        let mut data = self.data.write();
         let key_len = key.len();
         let val_len = val.len();
-        let prev = self.data.insert(key, val);
-        let prev_val_size = prev.as_ref().map(|v| v.len() + key_len).unwrap_or(0);
-        self.bytes = self.bytes + key_len + val_len - prev_val_size;
-        prev
+        let val_capacity = val.capacity();
+        let key_memory = key.capacity() + BTREE_ENTRY_OVERHEAD;
+        data.memory += val_capacity;
+        // an existing key keeps its first copy
+        match data.map.insert(key, (Bytes::from(val), val_capacity)) {
+            Some((prev, prev_capacity)) => {
+                data.bytes = data.bytes + val_len - prev.len();
+                data.memory -= prev_capacity;
+            }
+            None => {
+                data.bytes += key_len + val_len;
+                data.memory += key_memory;
+            }
+        }
     }
 
-    pub fn remove(&mut self, key: &ByteStr) -> Option<ByteString> {
-        let prev = self.data.remove(key);
-        let prev_size = prev.as_ref().map(|v| v.len() + key.len()).unwrap_or(0);
-        self.bytes -= prev_size;
-        prev
+    fn remove(&self, key: &ByteStr) {
+        let mut data = self.data.write();
+        if let Some((key, (val, val_capacity))) = data.map.remove_entry(key) {
+            data.bytes -= key.len() + val.len();
+            data.memory -= key.capacity() + val_capacity + BTREE_ENTRY_OVERHEAD;
+        }
     }
 
-    pub fn size(&self) -> usize {
-        self.data.len()
+    fn size(&self) -> usize {
+        self.data.read().map.len()
     }
 
-    pub fn size_in_bytes(&self) -> usize {
-        self.bytes
+    fn size_in_bytes(&self) -> usize {
+        self.data.read().bytes
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<BTreeRep>() + self.data.read().memory
+    }
+
+    fn iter_from<'a>(&'a self, start: Option<&ByteStr>) -> Box<dyn InternalIterator + 'a> {
+        Box::new(BTreeIter {
+            rep: self,
+            lower_bound: start.map_or(Bound::Unbounded, |start| Bound::Included(start.to_vec())),
+        })
+    }
+}
+
+/// Looks up each entry under the read lock on its own, so writers aren't
+/// blocked for the length of a scan.
+struct BTreeIter<'a> {
+    rep: &'a BTreeRep,
+    lower_bound: Bound<ByteString>,
+}
+
+impl InternalIterator for BTreeIter<'_> {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        let data = self.rep.data.read();
+        let next = data
+            .map
+            .range::<ByteString, _>((self.lower_bound.as_ref(), Bound::Unbounded))
+            .next();
+        Ok(next.map(|(key, (value, _))| {
+            self.lower_bound = Bound::Excluded(key.clone());
+            KeyValuePair::new(key.clone(), value.to_vec())
+        }))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeMap;
     use std::io::Cursor;
 
-    use crate::memtable::MemTable;
+    use crate::memtable::{MemTable, MemTableType};
+    use crate::merge_iterator::InternalIterator;
     use crate::wal::{CommandLog, LogRecord};
 
+    const TYPES: [MemTableType; 2] = [MemTableType::SkipList, MemTableType::BTree];
+
     impl MemTable {
         pub fn new_in_memory_log() -> MemTable {
-            MemTable {
-                data: BTreeMap::new(),
-                bytes: 0,
-            }
+            MemTable::new()
         }
     }
     #[test]
@@ -124,25 +311,71 @@ mod tests {
             log.log(&record).unwrap();
         }
         let vec = log.inner();
-        let mut log = CommandLog::new_in_memory(vec);
-
-        let table = MemTable::from_log(&mut log).unwrap();
-        assert_eq!(
-            table.get("key1".as_bytes().to_vec().as_ref()),
-            Some("value1".as_bytes().to_vec().as_ref())
-        );
+        for memtable_type in TYPES {
+            let mut log = CommandLog::new_in_memory(vec.clone());
+            let table = MemTable::from_log(&mut log, memtable_type).unwrap();
+            assert_eq!(
+                table.get("key1".as_bytes()),
+                Some("value1".as_bytes().to_vec())
+            );
+            assert_eq!(table.get("key2".as_bytes()), Some(vec![0]));
+            assert_eq!(3, table.size());
+        }
     }
 
     #[test]
     fn size_after_insert() {
-        let mut table: MemTable = MemTable::new_in_memory_log();
-        table.insert("key".as_bytes().to_vec(), "value".as_bytes().to_vec());
-        assert_eq!(8, table.size_in_bytes());
-        table.remove(&"key1".as_bytes().to_vec());
-        assert_eq!(8, table.size_in_bytes());
-        table.insert("key".as_bytes().to_vec(), "v".as_bytes().to_vec());
-        assert_eq!(4, table.size_in_bytes());
-        table.remove(&"key".as_bytes().to_vec());
-        assert_eq!(0, table.size_in_bytes());
+        for memtable_type in TYPES {
+            let table = MemTable::with_type(memtable_type);
+            table.insert("key".as_bytes().to_vec(), "value".as_bytes().to_vec());
+            assert_eq!(8, table.size_in_bytes());
+            table.remove("key1".as_bytes());
+            assert_eq!(8, table.size_in_bytes());
+            table.insert("key".as_bytes().to_vec(), "v".as_bytes().to_vec());
+            assert_eq!(4, table.size_in_bytes());
+            table.remove("key".as_bytes());
+            assert_eq!(0, table.size_in_bytes());
+        }
+    }
+
+    #[test]
+    fn memory_usage_counts_overhead() {
+        for memtable_type in TYPES {
+            let table = MemTable::with_type(memtable_type);
+            let empty = table.memory_usage();
+            assert_eq!(0, table.entries_memory_usage());
+            for i in 0..100 {
+                table.insert(format!("k_{}", i).into_bytes(), vec![0; 10]);
+            }
+            let used = table.memory_usage() - empty;
+            assert_eq!(used, table.entries_memory_usage());
+            assert!(used > 2 * table.size_in_bytes(), "{:?}", memtable_type);
+            for i in 0..100 {
+                table.remove(format!("k_{}", i).as_bytes());
+            }
+            assert_eq!(0, table.size_in_bytes());
+            // the skiplist keeps removed values until it is dropped
+            match memtable_type {
+                MemTableType::SkipList => assert!(table.memory_usage() - empty >= used),
+                MemTableType::BTree => assert_eq!(empty, table.memory_usage()),
+            }
+        }
+    }
+
+    #[test]
+    fn iter_from_skips_removed_keys() {
+        for memtable_type in TYPES {
+            let table = MemTable::with_type(memtable_type);
+            for key in ["a", "b", "c", "d"] {
+                table.insert(key.as_bytes().to_vec(), key.as_bytes().to_vec());
+            }
+            table.remove(b"c");
+            let mut iter = table.iter_from(Some(b"b"));
+            let mut keys = Vec::new();
+            while let Some(kv) = iter.next_entry().unwrap() {
+                keys.push(kv.key_cloned());
+            }
+            assert_eq!(vec![b"b".to_vec(), b"d".to_vec()], keys);
+        }
     }
 }
@@ -0,0 +1,231 @@
+//! Iteration in key order over several sorted sources at once, shared by
+//! compaction and scans.
+//!
+//! Memtables, tables and whole levels are all `InternalIterator`s. A
+//! `MergingIterator` combines any number of them through a binary heap, so
+//! each entry costs `O(log n)` comparisons for `n` sources, and returns
+//! every key once, with the value of the newest source holding it.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::vec;
+
+use crate::sstable_format::TableProperties;
+use crate::{ByteStr, KeyValuePair};
+
+/// A source of entries in key order, each key at most once.
+pub(crate) trait InternalIterator {
+    /// Next entry, or `None` once the source is exhausted.
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>>;
+}
+
+impl InternalIterator for vec::IntoIter<KeyValuePair> {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        Ok(self.next())
+    }
+}
+
+/// Head of one source in the heap of a `MergingIterator`.
+struct HeapEntry {
+    kv: KeyValuePair,
+    source: usize,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    /// Reversed, so the max-heap pops the smallest key first and, among
+    /// equal keys, the newest source.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .kv
+            .key_ref()
+            .cmp(self.kv.key_ref())
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+/// Merges sources given newest first. A key held by several sources is
+/// returned once, with the value of the newest of them; tombstones are
+/// returned like any other value.
+pub(crate) struct MergingIterator<'a> {
+    sources: Vec<Box<dyn InternalIterator + 'a>>,
+    heap: BinaryHeap<HeapEntry>,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub(crate) fn new(sources: Vec<Box<dyn InternalIterator + 'a>>) -> io::Result<Self> {
+        let mut iterator = MergingIterator {
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+        };
+        for source in 0..iterator.sources.len() {
+            iterator.advance(source)?;
+        }
+        Ok(iterator)
+    }
+
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        if let Some(kv) = self.sources[source].next_entry()? {
+            self.heap.push(HeapEntry { kv, source });
+        }
+        Ok(())
+    }
+}
+
+impl InternalIterator for MergingIterator<'_> {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        let top = match self.heap.pop() {
+            Some(top) => top,
+            None => return Ok(None),
+        };
+        self.advance(top.source)?;
+        // older values of the same key
+        while let Some(entry) = self.heap.peek() {
+            if entry.kv.key_ref() != top.kv.key_ref() {
+                break;
+            }
+            let source = self.heap.pop().unwrap().source;
+            self.advance(source)?;
+        }
+        Ok(Some(top.kv))
+    }
+}
+
+/// Chains the tables of a level whose key ranges don't overlap, given in
+/// key order, into one source.
+pub(crate) struct LevelIterator<'a> {
+    tables: VecDeque<Box<dyn InternalIterator + 'a>>,
+}
+
+impl InternalIterator for LevelIterator<'_> {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        while let Some(table) = self.tables.front_mut() {
+            if let Some(kv) = table.next_entry()? {
+                return Ok(Some(kv));
+            }
+            self.tables.pop_front();
+        }
+        Ok(None)
+    }
+}
+
+/// Positions of the tables of a level in key order, if no two of them
+/// overlap and all their key ranges are known.
+pub(crate) fn key_order(tables: &[&TableProperties]) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..tables.len()).collect();
+    order.sort_by(|a, b| tables[*a].smallest_key.cmp(&tables[*b].smallest_key));
+    for pair in order.windows(2) {
+        let largest = tables[pair[0]].largest_key.as_ref()?;
+        let smallest = tables[pair[1]].smallest_key.as_ref()?;
+        if largest >= smallest {
+            return None;
+        }
+    }
+    Some(order)
+}
+
+/// Sources for the tables of a level, given oldest first as levels keep
+/// them: one `LevelIterator` if `key_order` found an order, else one source
+/// per table, newest first.
+pub(crate) fn level_sources<'a>(
+    key_order: Option<Vec<usize>>,
+    tables: Vec<Box<dyn InternalIterator + 'a>>,
+) -> Vec<Box<dyn InternalIterator + 'a>> {
+    match key_order {
+        Some(order) if tables.len() > 1 => {
+            let mut tables: Vec<Option<Box<dyn InternalIterator + 'a>>> =
+                tables.into_iter().map(Some).collect();
+            let tables = order.iter().filter_map(|i| tables[*i].take()).collect();
+            vec![Box::new(LevelIterator { tables })]
+        }
+        _ => tables.into_iter().rev().collect(),
+    }
+}
+
+/// The entries of `input` up to `end` (inclusive, `None` for unbounded),
+/// tombstones included.
+pub(crate) fn collect_range(
+    input: &mut dyn InternalIterator,
+    end: Option<&ByteStr>,
+) -> io::Result<Vec<KeyValuePair>> {
+    let mut entries = Vec::new();
+    while let Some(kv) = input.next_entry()? {
+        if end.is_some_and(|end| kv.key_ref() > end) {
+            break;
+        }
+        entries.push(kv);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::merge_iterator::{key_order, InternalIterator, MergingIterator};
+    use crate::sstable_format::TableProperties;
+    use crate::KeyValuePair;
+
+    fn source(entries: &[(&str, &str)]) -> Box<dyn InternalIterator> {
+        let entries: Vec<KeyValuePair> = entries
+            .iter()
+            .map(|(key, value)| {
+                KeyValuePair::new(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn newest_source_wins() -> io::Result<()> {
+        let mut iterator = MergingIterator::new(vec![
+            source(&[("b", "new"), ("d", "new")]),
+            source(&[("a", "old"), ("b", "old"), ("c", "old"), ("d", "old")]),
+            source(&[]),
+            source(&[("b", "oldest"), ("e", "oldest")]),
+        ])?;
+        let mut merged = Vec::new();
+        while let Some(kv) = iterator.next_entry()? {
+            merged.push((kv.key_cloned(), kv.value_owned()));
+        }
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = [
+            ("a", "old"),
+            ("b", "new"),
+            ("c", "old"),
+            ("d", "new"),
+            ("e", "oldest"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect();
+        assert_eq!(expected, merged);
+        Ok(())
+    }
+
+    #[test]
+    fn key_order_of_disjoint_tables() {
+        let table = |smallest: &str, largest: &str| TableProperties {
+            smallest_key: Some(smallest.as_bytes().to_vec()),
+            largest_key: Some(largest.as_bytes().to_vec()),
+            ..TableProperties::default()
+        };
+        let (a, b, c) = (table("m", "p"), table("a", "c"), table("d", "f"));
+        assert_eq!(Some(vec![1, 2, 0]), key_order(&[&a, &b, &c]));
+        let overlapping = table("c", "e");
+        assert_eq!(None, key_order(&[&a, &b, &overlapping]));
+    }
+}
@@ -0,0 +1,179 @@
+//! Token-bucket limit on the bytes flushes and compactions write per second,
+//! so they leave disk bandwidth to foreground reads.
+//!
+//! Tokens are added every `REFILL_PERIOD`, and at most one period's worth is
+//! kept, which bounds bursts. A write that finds tokens left goes ahead even
+//! if it needs more than there are; the bucket goes into debt and later
+//! writes wait until it is paid off. Flushes are `IoPriority::High`: while
+//! one waits, compaction writes don't get any tokens.
+//!
+//! With auto-tuning the configured rate is an upper bound. Every
+//! `TUNE_PERIODS` refills the rate goes up if writes had to wait in most of
+//! them and down if they rarely did, so the limit only gets close to the
+//! configured rate while compactions actually need it.
+
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::config::Config;
+
+const REFILL_PERIOD: Duration = Duration::from_millis(100);
+const TUNE_PERIODS: u32 = 10;
+/// Auto-tuning never goes below the configured rate divided by this.
+const MIN_RATE_DIVISOR: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IoPriority {
+    /// Compaction writes.
+    Low,
+    /// Memtable flushes, which writes to the memtable may be waiting for.
+    High,
+}
+
+pub(crate) struct RateLimiter {
+    max_bytes_per_sec: u64,
+    auto_tune: bool,
+    bucket: Mutex<Bucket>,
+    /// Signalled when a high priority write got its tokens.
+    high_done: Condvar,
+}
+
+struct Bucket {
+    bytes_per_sec: u64,
+    /// Negative while in debt.
+    available: i64,
+    last_refill: Instant,
+    high_waiting: usize,
+    /// Refill periods since the last auto-tuning, and how many of them
+    /// some write had to wait in.
+    periods: u32,
+    drained_periods: u32,
+    drained: bool,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64, auto_tune: bool) -> RateLimiter {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        RateLimiter {
+            max_bytes_per_sec: bytes_per_sec,
+            auto_tune,
+            bucket: Mutex::new(Bucket {
+                bytes_per_sec,
+                available: per_period(bytes_per_sec),
+                last_refill: Instant::now(),
+                high_waiting: 0,
+                periods: 0,
+                drained_periods: 0,
+                drained: false,
+            }),
+            high_done: Condvar::new(),
+        }
+    }
+
+    /// The limiter `Config::rate_limit_bytes_per_sec` asks for, if any.
+    pub(crate) fn from_config(config: &Config) -> Option<RateLimiter> {
+        config
+            .rate_limit_bytes_per_sec
+            .map(|rate| RateLimiter::new(rate, config.rate_limit_auto_tune))
+    }
+
+    /// Blocks until `bytes` may be written.
+    pub(crate) fn request(&self, bytes: usize, priority: IoPriority) {
+        let mut bucket = self.bucket.lock();
+        if priority == IoPriority::High {
+            bucket.high_waiting += 1;
+        }
+        loop {
+            self.refill(&mut bucket);
+            let turn = priority == IoPriority::High || bucket.high_waiting == 0;
+            if turn && bucket.available > 0 {
+                bucket.available -= bytes as i64;
+                break;
+            }
+            bucket.drained = true;
+            let next_refill = bucket.last_refill + REFILL_PERIOD;
+            self.high_done.wait_until(&mut bucket, next_refill);
+        }
+        if priority == IoPriority::High {
+            bucket.high_waiting -= 1;
+            self.high_done.notify_all();
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let elapsed = bucket.last_refill.elapsed();
+        let periods = (elapsed.as_nanos() / REFILL_PERIOD.as_nanos()) as u32;
+        if periods == 0 {
+            return;
+        }
+        bucket.last_refill += REFILL_PERIOD * periods;
+        let limit = per_period(bucket.bytes_per_sec);
+        bucket.available = bucket
+            .available
+            .saturating_add(limit.saturating_mul(i64::from(periods)))
+            .min(limit);
+        if !self.auto_tune {
+            return;
+        }
+        bucket.periods += periods;
+        if bucket.drained {
+            bucket.drained_periods += 1;
+            bucket.drained = false;
+        }
+        if bucket.periods >= TUNE_PERIODS {
+            let drained_percent = bucket.drained_periods * 100 / bucket.periods;
+            let rate = bucket.bytes_per_sec;
+            bucket.bytes_per_sec = if drained_percent >= 90 {
+                (rate + rate / 20).max(rate + 1)
+            } else if drained_percent < 50 {
+                rate - rate / 21
+            } else {
+                rate
+            }
+            .clamp(
+                self.max_bytes_per_sec / MIN_RATE_DIVISOR,
+                self.max_bytes_per_sec,
+            )
+            .max(1);
+            bucket.periods = 0;
+            bucket.drained_periods = 0;
+        }
+    }
+}
+
+fn per_period(bytes_per_sec: u64) -> i64 {
+    let bytes = u128::from(bytes_per_sec) * REFILL_PERIOD.as_nanos() / 1_000_000_000;
+    i64::try_from(bytes).unwrap_or(i64::MAX).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limiter::{IoPriority, RateLimiter};
+
+    #[test]
+    fn writes_are_spread_over_time() {
+        // 100 KiB per refill period, so 300 KiB need at least two refills
+        let limiter = RateLimiter::new(1024 * 1024, false);
+        let started = Instant::now();
+        for _ in 0..300 {
+            limiter.request(1024, IoPriority::Low);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn auto_tuning_lowers_an_unused_rate() {
+        let limiter = RateLimiter::new(1024 * 1024, true);
+        limiter.request(1024, IoPriority::High);
+        thread::sleep(Duration::from_millis(1100));
+        limiter.request(1024, IoPriority::High);
+        assert!(limiter.bucket.lock().bytes_per_sec < 1024 * 1024);
+    }
+}
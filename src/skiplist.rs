@@ -0,0 +1,408 @@
+//! Concurrent skiplist, the default memtable representation.
+//!
+//! Nodes and values live in append-only arenas and refer to each other by
+//! index. Writers link new nodes in with compare-and-swap, so any number of
+//! them can insert at once, and readers never wait: a node is only reachable
+//! once it is fully written. Nodes are never unlinked; removing a key or
+//! overwriting its value swaps the value index, and the old value stays in
+//! the arena until the memtable is dropped, and counts towards its memory.
+
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::merge_iterator::InternalIterator;
+use crate::{ByteStr, ByteString, KeyValuePair};
+
+const MAX_HEIGHT: usize = 12;
+/// Index of the head node. It also ends every level, since no node links
+/// back to it.
+const HEAD: usize = 0;
+/// Value index of a removed key.
+const REMOVED: usize = usize::MAX;
+
+/// Slots in the first arena chunk; each further chunk is twice as big.
+const FIRST_CHUNK: usize = 16;
+const CHUNKS: usize = 48;
+
+type Chunk<T> = Box<[OnceLock<T>]>;
+
+/// Append-only storage for concurrent writers. Slots are handed out by an
+/// atomic counter and never move, so a slot whose index was published can
+/// be read without locking.
+struct Arena<T> {
+    chunks: Box<[OnceLock<Chunk<T>>]>,
+    next: AtomicUsize,
+    /// Bytes of the chunks allocated so far.
+    allocated: AtomicUsize,
+}
+
+impl<T> Arena<T> {
+    fn new() -> Arena<T> {
+        Arena {
+            chunks: (0..CHUNKS).map(|_| OnceLock::new()).collect(),
+            next: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(CHUNKS * mem::size_of::<OnceLock<Chunk<T>>>()),
+        }
+    }
+
+    fn alloc(&self, value: T) -> usize {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        let (chunk, offset) = locate(index);
+        let chunk = self.chunks[chunk].get_or_init(|| {
+            let slots = FIRST_CHUNK << chunk;
+            self.allocated
+                .fetch_add(slots * mem::size_of::<OnceLock<T>>(), Ordering::Relaxed);
+            (0..slots).map(|_| OnceLock::new()).collect()
+        });
+        if chunk[offset].set(value).is_err() {
+            unreachable!("arena slot {} handed out twice", index);
+        }
+        index
+    }
+
+    fn get(&self, index: usize) -> &T {
+        let (chunk, offset) = locate(index);
+        self.chunks[chunk]
+            .get()
+            .and_then(|chunk| chunk[offset].get())
+            .expect("arena slot is allocated")
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+/// Chunk and offset of an arena slot.
+fn locate(index: usize) -> (usize, usize) {
+    let n = index / FIRST_CHUNK + 1;
+    let chunk = (usize::BITS - 1 - n.leading_zeros()) as usize;
+    (chunk, index - FIRST_CHUNK * ((1 << chunk) - 1))
+}
+
+struct Node {
+    key: ByteString,
+    value: AtomicUsize,
+    next: Box<[AtomicUsize]>,
+}
+
+impl Node {
+    fn new(key: ByteString, value: usize, height: usize) -> Node {
+        Node {
+            key,
+            value: AtomicUsize::new(value),
+            next: (0..height).map(|_| AtomicUsize::new(HEAD)).collect(),
+        }
+    }
+}
+
+pub(crate) struct SkipList {
+    nodes: Arena<Node>,
+    values: Arena<Bytes>,
+    len: AtomicUsize,
+    bytes: AtomicUsize,
+    /// Heap memory of keys, values and next pointers, outside the arenas.
+    heap: AtomicUsize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SkipList {
+    pub(crate) fn new() -> SkipList {
+        let nodes = Arena::new();
+        nodes.alloc(Node::new(Vec::new(), REMOVED, MAX_HEIGHT));
+        SkipList {
+            nodes,
+            values: Arena::new(),
+            len: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            heap: AtomicUsize::new(MAX_HEIGHT * mem::size_of::<AtomicUsize>()),
+        }
+    }
+
+    /// Number of keys, removed ones not included.
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Size of the keys and current values.
+    pub(crate) fn size_in_bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Memory held by the list, including values that were overwritten or
+    /// removed and nodes of inserts that lost a race.
+    pub(crate) fn memory_usage(&self) -> usize {
+        mem::size_of::<SkipList>()
+            + self.nodes.memory_usage()
+            + self.values.memory_usage()
+            + self.heap.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn get(&self, key: &ByteStr) -> Option<&Bytes> {
+        let node = self.seek(key);
+        if node == HEAD || self.node(node).key != key {
+            return None;
+        }
+        match self.node(node).value.load(Ordering::Acquire) {
+            REMOVED => None,
+            value => Some(self.values.get(value)),
+        }
+    }
+
+    pub(crate) fn insert(&self, key: ByteString, value: ByteString) {
+        let sizes = (key.len(), value.len());
+        self.heap.fetch_add(value.capacity(), Ordering::Relaxed);
+        let value = self.values.alloc(Bytes::from(value));
+        let height = random_height();
+        let mut preds = [HEAD; MAX_HEIGHT];
+        let mut succs = [HEAD; MAX_HEIGHT];
+        // the key moves into the node once it is needed
+        let mut key = Some(key);
+        let mut node = HEAD;
+        loop {
+            let found = match &key {
+                Some(key) => self.find(key, &mut preds, &mut succs),
+                None => self.find(&self.node(node).key, &mut preds, &mut succs),
+            };
+            if found {
+                // a node allocated by an earlier attempt stays unused
+                self.replace_value(succs[0], value, sizes);
+                return;
+            }
+            if let Some(key) = key.take() {
+                let next_size = height * mem::size_of::<AtomicUsize>();
+                self.heap
+                    .fetch_add(key.capacity() + next_size, Ordering::Relaxed);
+                node = self.nodes.alloc(Node::new(key, value, height));
+            }
+            let new = self.node(node);
+            for (level, next) in new.next.iter().enumerate() {
+                next.store(succs[level], Ordering::Relaxed);
+            }
+            let pred = &self.node(preds[0]).next[0];
+            if pred
+                .compare_exchange(succs[0], node, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(sizes.0 + sizes.1, Ordering::Relaxed);
+        // the node is in the list now; the higher levels only speed up search
+        let new = self.node(node);
+        for level in 1..height {
+            loop {
+                let pred = &self.node(preds[level]).next[level];
+                if pred
+                    .compare_exchange(succs[level], node, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    break;
+                }
+                self.find(&new.key, &mut preds, &mut succs);
+                new.next[level].store(succs[level], Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &ByteStr) {
+        let node = self.seek(key);
+        if node == HEAD || self.node(node).key != key {
+            return;
+        }
+        let old = self.node(node).value.swap(REMOVED, Ordering::AcqRel);
+        if old != REMOVED {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            self.bytes
+                .fetch_sub(key.len() + self.values.get(old).len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Iterates over the keys from `start` on.
+    pub(crate) fn iter_from(&self, start: Option<&ByteStr>) -> SkipListIter<'_> {
+        let node = match start {
+            Some(start) => self.seek(start),
+            None => self.node(HEAD).next[0].load(Ordering::Acquire),
+        };
+        SkipListIter { list: self, node }
+    }
+
+    fn node(&self, index: usize) -> &Node {
+        self.nodes.get(index)
+    }
+
+    /// Swaps in `value` for the key of `node`.
+    fn replace_value(&self, node: usize, value: usize, (key_len, value_len): (usize, usize)) {
+        let old = self.node(node).value.swap(value, Ordering::AcqRel);
+        if old == REMOVED {
+            self.len.fetch_add(1, Ordering::Relaxed);
+            self.bytes.fetch_add(key_len + value_len, Ordering::Relaxed);
+        } else {
+            // add before subtracting, so the size never dips below the truth
+            self.bytes.fetch_add(value_len, Ordering::Relaxed);
+            self.bytes
+                .fetch_sub(self.values.get(old).len(), Ordering::Relaxed);
+        }
+    }
+
+    /// First node with a key not less than `key`, `HEAD` if there is none.
+    fn seek(&self, key: &ByteStr) -> usize {
+        let mut pred = HEAD;
+        let mut succ = HEAD;
+        for level in (0..MAX_HEIGHT).rev() {
+            (pred, succ) = self.last_before(pred, level, key);
+        }
+        succ
+    }
+
+    /// Fills in, for every level, the last node with a key less than `key`
+    /// and its successor. Returns whether the level 0 successor holds `key`.
+    fn find(
+        &self,
+        key: &ByteStr,
+        preds: &mut [usize; MAX_HEIGHT],
+        succs: &mut [usize; MAX_HEIGHT],
+    ) -> bool {
+        let mut pred = HEAD;
+        for level in (0..MAX_HEIGHT).rev() {
+            (pred, succs[level]) = self.last_before(pred, level, key);
+            preds[level] = pred;
+        }
+        succs[0] != HEAD && self.node(succs[0]).key == key
+    }
+
+    /// Walks `level` from `pred` to the last node with a key less than `key`
+    /// and returns it with the successor it was compared against. Loading
+    /// the successor again could see a smaller key inserted meanwhile.
+    fn last_before(&self, mut pred: usize, level: usize, key: &ByteStr) -> (usize, usize) {
+        loop {
+            let next = self.node(pred).next[level].load(Ordering::Acquire);
+            if next == HEAD || self.node(next).key.as_slice() >= key {
+                return (pred, next);
+            }
+            pred = next;
+        }
+    }
+}
+
+fn random_height() -> usize {
+    let mut rng = rand::thread_rng();
+    let mut height = 1;
+    while height < MAX_HEIGHT && rng.gen_ratio(1, 4) {
+        height += 1;
+    }
+    height
+}
+
+pub(crate) struct SkipListIter<'a> {
+    list: &'a SkipList,
+    node: usize,
+}
+
+impl InternalIterator for SkipListIter<'_> {
+    fn next_entry(&mut self) -> std::io::Result<Option<KeyValuePair>> {
+        while self.node != HEAD {
+            let node = self.list.node(self.node);
+            self.node = node.next[0].load(Ordering::Acquire);
+            let value = node.value.load(Ordering::Acquire);
+            if value != REMOVED {
+                let value = self.list.values.get(value).to_vec();
+                return Ok(Some(KeyValuePair::new(node.key.clone(), value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::sync::Arc;
+    use std::thread;
+
+    use rand::Rng;
+
+    use crate::merge_iterator::InternalIterator;
+    use crate::skiplist::{locate, SkipList};
+
+    #[test]
+    fn arena_chunks_double() {
+        assert_eq!((0, 0), locate(0));
+        assert_eq!((0, 15), locate(15));
+        assert_eq!((1, 0), locate(16));
+        assert_eq!((1, 31), locate(47));
+        assert_eq!((2, 0), locate(48));
+    }
+
+    #[test]
+    fn concurrent_writers() -> io::Result<()> {
+        let list = Arc::new(SkipList::new());
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        let key = format!("k_{:05}", i * 4 + writer).into_bytes();
+                        list.insert(key, format!("v_{}", i).into_bytes());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(8000, list.len());
+        let mut iter = list.iter_from(None);
+        let mut previous = None;
+        let mut count = 0;
+        while let Some(kv) = iter.next_entry()? {
+            assert!(previous < Some(kv.key_cloned()));
+            previous = Some(kv.key_cloned());
+            count += 1;
+        }
+        assert_eq!(8000, count);
+        Ok(())
+    }
+
+    #[test]
+    fn matches_btree_map() -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+        let list = SkipList::new();
+        let mut map = BTreeMap::new();
+        for _ in 0..5000 {
+            let key = format!("k_{}", rng.gen::<u32>() % 1000).into_bytes();
+            if rng.gen_ratio(1, 5) {
+                list.remove(&key);
+                map.remove(&key);
+            } else {
+                let value = format!("v_{}", rng.gen::<u32>()).into_bytes();
+                list.insert(key.clone(), value.clone());
+                map.insert(key, value);
+            }
+        }
+        assert_eq!(map.len(), list.len());
+        let bytes: usize = map.iter().map(|(key, value)| key.len() + value.len()).sum();
+        assert_eq!(bytes, list.size_in_bytes());
+        for (key, value) in &map {
+            assert_eq!(Some(value.as_slice()), list.get(key).map(|v| v.as_ref()));
+        }
+        let mut iter = list.iter_from(Some(b"k_5"));
+        for (key, value) in map.range(b"k_5".to_vec()..) {
+            let kv = iter.next_entry()?.unwrap();
+            assert_eq!((key, value), (&kv.key_cloned(), &kv.value_owned()));
+        }
+        assert!(iter.next_entry()?.is_none());
+        Ok(())
+    }
+}
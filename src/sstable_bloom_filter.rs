@@ -23,6 +23,17 @@ impl SstableBloomFilter {
         Ok(SstableBloomFilter { bloom_filter })
     }
 
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<SstableBloomFilter> {
+        let bloom_filter =
+            bincode::deserialize(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(SstableBloomFilter { bloom_filter })
+    }
+
+    pub(crate) fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        bincode::serialize(&self.bloom_filter)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
     pub(crate) fn contains(&self, key: &ByteStr) -> bool {
         self.bloom_filter.contains(key)
     }
@@ -30,10 +41,4 @@ impl SstableBloomFilter {
     pub(crate) fn insert(&mut self, key: &ByteStr) {
         self.bloom_filter.insert(key);
     }
-
-    pub(crate) fn write_to_file(&self, path: &Path) -> io::Result<()> {
-        let bloom_filter_file = OpenOptions::new().write(true).create(true).open(path)?;
-        bincode::serialize_into(bloom_filter_file, &self.bloom_filter)
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
-    }
 }
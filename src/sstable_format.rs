@@ -0,0 +1,951 @@
+//! Single-file table layout:
+//!
+//! ```text
+//! [data block]...[data block][index block][filter block][properties block][footer]
+//! ```
+//!
+//! The footer has a fixed size and sits at the very end of the file, so a
+//! reader starts there to find the other blocks. Data blocks are described in
+//! `block`; the index maps the last key of every data block to its handle,
+//! see `sstable_index`.
+//! Every block is followed by a trailer with its `CompressionType` (one byte)
+//! and a CRC32C of the block and that byte (u32), checked whenever the block
+//! is read. A table written with a Zstd dictionary stores it in a meta block
+//! referenced from the properties.
+//!
+//! Version 4 and older files store the index as a serialized map of the first
+//! key of every data block to its offset. Version 3 files have only the
+//! compression byte in data block trailers and
+//! whole-file checksums in the properties; version 2 files have no block
+//! trailers. Version 1 files hold flat
+//! `len|len|key|value` records instead of blocks, like legacy data files, and
+//! the index points at every 100th record.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use crc::crc32;
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, BlockBuilder, BlockIter};
+use crate::block_cache::BlockCache;
+use crate::checksums::Checksums;
+use crate::compaction::CompactionFilter;
+use crate::compression::{self, CompressionOptions, CompressionType};
+use crate::config::Config;
+use crate::datafile::{ReadOnlyDataFile, SizedFile, WriteableDataFile};
+use crate::fs_utils;
+use crate::rate_limiter::{IoPriority, RateLimiter};
+use crate::sstable_bloom_filter::SstableBloomFilter;
+use crate::sstable_index::{BlockIndex, IndexBuilder, IndexIter, SstableIndex, TableIndex};
+use crate::sstable_metadata::{SsTableMetadata, TableFormat};
+use crate::table_cache::TableCache;
+use crate::{ByteStr, ByteString, KeyValuePair};
+
+pub(crate) const TABLE_MAGIC: u64 = u64::from_le_bytes(*b"LSMTABLE");
+pub(crate) const FORMAT_VERSION: u32 = 5;
+const RECORDS_FORMAT_VERSION: u32 = 1;
+const BLOCKS_FORMAT_VERSION: u32 = 2;
+const COMPRESSED_FORMAT_VERSION: u32 = 3;
+const CHECKSUMMED_FORMAT_VERSION: u32 = 4;
+const BLOCK_TRAILER_SIZE: usize = 1 + 4;
+const FOOTER_SIZE: u64 = 3 * BlockHandle::ENCODED_SIZE + 4 + 8;
+const BLOCK_SIZE: usize = 4096;
+
+/// How records are laid out in the data part of a table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DataLayout {
+    /// Flat records, used by legacy and version 1 tables.
+    Records,
+    /// Prefix-compressed blocks, see `block`.
+    Blocks,
+    /// Blocks followed by a trailer with their compression type.
+    CompressedBlocks,
+    /// Blocks followed by a trailer with their compression type and CRC32C.
+    ChecksummedBlocks,
+}
+
+impl DataLayout {
+    fn from_version(version: u32) -> DataLayout {
+        match version {
+            RECORDS_FORMAT_VERSION => DataLayout::Records,
+            BLOCKS_FORMAT_VERSION => DataLayout::Blocks,
+            COMPRESSED_FORMAT_VERSION => DataLayout::CompressedBlocks,
+            _ => DataLayout::ChecksummedBlocks,
+        }
+    }
+}
+
+/// Settings for writing tables, shared by flushes and compactions.
+#[derive(Clone)]
+pub(crate) struct TableOptions {
+    pub(crate) base_path: String,
+    pub(crate) compression: CompressionOptions,
+    pub(crate) target_file_size: u64,
+    pub(crate) compaction_threads: usize,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    pub(crate) block_cache: Option<Arc<BlockCache>>,
+    pub(crate) table_cache: Arc<TableCache>,
+}
+
+impl TableOptions {
+    pub(crate) fn from_config(config: &Config) -> io::Result<TableOptions> {
+        Ok(TableOptions {
+            base_path: config.base_path.clone(),
+            compression: CompressionOptions::from_config(config)?,
+            target_file_size: config.target_file_size_bytes.max(1),
+            compaction_threads: config.compaction_threads.max(1),
+            rate_limiter: RateLimiter::from_config(config).map(Arc::new),
+            compaction_filter: config.compaction_filter.clone(),
+            block_cache: BlockCache::from_config(config).map(Arc::new),
+            table_cache: Arc::new(TableCache::from_config(config)),
+        })
+    }
+}
+
+/// Location of a block inside a table file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BlockHandle {
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+impl BlockHandle {
+    pub(crate) const ENCODED_SIZE: u64 = 16;
+
+    /// Handle of the block from `start` up to `end`.
+    pub(crate) fn between(start: u64, end: u64) -> BlockHandle {
+        BlockHandle {
+            offset: start,
+            size: end - start,
+        }
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.offset)?;
+        writer.write_u64::<LittleEndian>(self.size)
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> io::Result<BlockHandle> {
+        let offset = reader.read_u64::<LittleEndian>()?;
+        let size = reader.read_u64::<LittleEndian>()?;
+        Ok(BlockHandle { offset, size })
+    }
+
+    fn read_block<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Vec<u8>> {
+        reader.seek(SeekFrom::Start(self.offset))?;
+        let mut block = vec![0u8; self.size as usize];
+        reader.read_exact(&mut block)?;
+        Ok(block)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Footer {
+    pub(crate) index: BlockHandle,
+    pub(crate) filter: BlockHandle,
+    pub(crate) properties: BlockHandle,
+    pub(crate) version: u32,
+}
+
+impl Footer {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.index.write_to(writer)?;
+        self.filter.write_to(writer)?;
+        self.properties.write_to(writer)?;
+        writer.write_u32::<LittleEndian>(self.version)?;
+        writer.write_u64::<LittleEndian>(TABLE_MAGIC)
+    }
+
+    fn read_from<R: Read + Seek>(reader: &mut R, path: &Path) -> io::Result<Footer> {
+        let file_size = reader.size()?;
+        if file_size < FOOTER_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} is too small to be an sstable", path),
+            ));
+        }
+        reader.seek(SeekFrom::Start(file_size - FOOTER_SIZE))?;
+        let index = BlockHandle::read_from(reader)?;
+        let filter = BlockHandle::read_from(reader)?;
+        let properties = BlockHandle::read_from(reader)?;
+        let version = reader.read_u32::<LittleEndian>()?;
+        let magic = reader.read_u64::<LittleEndian>()?;
+        if magic != TABLE_MAGIC {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} has a bad magic number {:016x}", path, magic),
+            ));
+        }
+        if !(RECORDS_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} has unsupported format version {}", path, version),
+            ));
+        }
+        Ok(Footer {
+            index,
+            filter,
+            properties,
+            version,
+        })
+    }
+}
+
+/// Statistics of a table, computed when it is written and stored in its
+/// properties block. Fields that a table predates are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableProperties {
+    #[serde(skip)]
+    pub id: u128,
+    #[serde(skip)]
+    pub level: u8,
+    /// Size of the data part of the table in bytes.
+    #[serde(skip)]
+    pub data_size: u64,
+    #[serde(default)]
+    pub smallest_key: Option<ByteString>,
+    #[serde(default)]
+    pub largest_key: Option<ByteString>,
+    #[serde(default)]
+    pub entries: Option<u64>,
+    #[serde(default)]
+    pub tombstones: Option<u64>,
+    /// Ids of the oldest and newest flushed tables whose entries this table
+    /// holds. Entries carry no sequence numbers of their own, so table ids
+    /// order the data instead.
+    #[serde(default)]
+    pub sequence_range: Option<(u128, u128)>,
+}
+
+impl TableProperties {
+    /// Orders tables by the newest data they hold. Tables that predate the
+    /// sequence range are ordered by id.
+    pub(crate) fn newest_sequence(&self) -> (u128, u128) {
+        let newest = self.sequence_range.map_or(self.id, |range| range.1);
+        (newest, self.id)
+    }
+
+    /// Whether `key` may be in the table, `true` when the key range is unknown.
+    pub(crate) fn may_contain(&self, key: &ByteStr) -> bool {
+        let above_smallest = self.smallest_key.as_deref().is_none_or(|k| key >= k);
+        let below_largest = self.largest_key.as_deref().is_none_or(|k| key <= k);
+        above_smallest && below_largest
+    }
+}
+
+/// Contents of the properties block.
+#[derive(Serialize, Deserialize)]
+struct PropertiesBlock {
+    data_size: u64,
+    #[serde(default)]
+    properties: TableProperties,
+    /// Whole-file checksums of version 2 and 3 tables, which have no block
+    /// checksums.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) checksums: Option<Checksums>,
+    #[serde(default)]
+    pub(crate) compression_dictionary: Option<BlockHandle>,
+    /// Whether the index block is the top level of a partitioned index.
+    #[serde(default)]
+    partitioned_index: bool,
+}
+
+/// Appends the trailer of a block: its compression type, then the CRC32C of
+/// the block and the compression type.
+fn append_trailer(block: &mut Vec<u8>, codec: CompressionType) {
+    block.push(codec as u8);
+    let crc = crc32::checksum_castagnoli(block);
+    block.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// Decodes the blocks of one table.
+#[derive(Clone)]
+pub(crate) struct BlockReader {
+    layout: DataLayout,
+    path: PathBuf,
+    dictionary: Option<Arc<Vec<u8>>>,
+    /// Table number the block cache knows the blocks by.
+    file_number: u128,
+    cache: Option<Arc<BlockCache>>,
+}
+
+impl BlockReader {
+    fn corrupted<E: Display>(&self, offset: u64, reason: E) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "corrupted block in {:?} at offset {}: {}",
+                self.path, offset, reason
+            ),
+        )
+    }
+
+    /// Strips the trailer of the block read at `offset`, checking its CRC32C
+    /// if the table has one.
+    fn strip_trailer(&self, block: Bytes, offset: u64) -> io::Result<(CompressionType, Bytes)> {
+        let codec_pos = match self.layout {
+            DataLayout::Records | DataLayout::Blocks => return Ok((CompressionType::None, block)),
+            DataLayout::CompressedBlocks => block
+                .len()
+                .checked_sub(1)
+                .ok_or_else(|| self.corrupted(offset, "missing trailer"))?,
+            DataLayout::ChecksummedBlocks => {
+                if block.len() < BLOCK_TRAILER_SIZE {
+                    return Err(self.corrupted(offset, "missing trailer"));
+                }
+                let crc_pos = block.len() - 4;
+                let expected = LittleEndian::read_u32(&block[crc_pos..]);
+                if crc32::checksum_castagnoli(&block[..crc_pos]) != expected {
+                    return Err(self.corrupted(offset, "checksum mismatch"));
+                }
+                crc_pos - 1
+            }
+        };
+        let codec =
+            CompressionType::from_u8(block[codec_pos]).map_err(|e| self.corrupted(offset, e))?;
+        Ok((codec, block.slice(..codec_pos)))
+    }
+
+    fn read_meta<R: Read + Seek>(&self, reader: &mut R, handle: BlockHandle) -> io::Result<Bytes> {
+        let block = Bytes::from(handle.read_block(reader)?);
+        let (codec, block) = self.strip_trailer(block, handle.offset)?;
+        compression::decompress(codec, block, None).map_err(|e| self.corrupted(handle.offset, e))
+    }
+
+    /// Reads the data block or index partition at `handle`, from the block
+    /// cache if it's there.
+    /// Point lookups add the blocks they read to the cache; scans and
+    /// compactions pass `fill_cache: false` so they don't push out the
+    /// blocks lookups need.
+    fn read(
+        &self,
+        data: &ReadOnlyDataFile,
+        handle: BlockHandle,
+        fill_cache: bool,
+    ) -> io::Result<Arc<Block>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.read_uncached(data, handle).map(Arc::new),
+        };
+        if let Some(block) = cache.get(self.file_number, handle.offset) {
+            return Ok(block);
+        }
+        let block = Arc::new(self.read_uncached(data, handle)?);
+        if fill_cache {
+            cache.insert(self.file_number, handle.offset, block.clone());
+        }
+        Ok(block)
+    }
+
+    fn read_uncached(&self, data: &ReadOnlyDataFile, handle: BlockHandle) -> io::Result<Block> {
+        let start = handle.offset;
+        let (codec, block) = self.strip_trailer(data.read_block(start, handle.size)?, start)?;
+        let dictionary = self.dictionary.as_ref().map(|d| d.as_slice());
+        let block = compression::decompress(codec, block, dictionary)
+            .map_err(|e| self.corrupted(start, e))?;
+        Block::new(block).map_err(|e| self.corrupted(start, e))
+    }
+}
+
+/// In-memory part of an open table, shared by the sync and tokio engines.
+pub(crate) struct SsTableMeta {
+    pub(crate) metadata: SsTableMetadata,
+    pub(crate) index: TableIndex,
+    pub(crate) bloom_filter: SstableBloomFilter,
+    /// The data part starts at offset 0 of the data file and is
+    /// `properties.data_size` bytes long.
+    pub(crate) properties: TableProperties,
+    pub(crate) blocks: BlockReader,
+}
+
+impl SsTableMeta {
+    /// Loads a table from its metadata file (legacy layout) or its `.sst` file.
+    /// Checksums are not verified here, data blocks are checked when read and
+    /// the rest by [`SsTableMeta::verify_checksums`]. Data blocks are cached
+    /// in `cache`, if given.
+    pub(crate) fn load(path: &Path, cache: Option<Arc<BlockCache>>) -> io::Result<SsTableMeta> {
+        let is_legacy = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with("metadata_"))
+            .unwrap_or(false);
+        if is_legacy {
+            Self::load_legacy(path)
+        } else {
+            Self::load_single_file(path, cache)
+        }
+    }
+
+    /// Loads the table again, sharing the block cache.
+    pub(crate) fn reload(&self) -> io::Result<SsTableMeta> {
+        Self::load(&self.metadata.table_path(), self.blocks.cache.clone())
+    }
+
+    fn load_legacy(metadata_path: &Path) -> io::Result<SsTableMeta> {
+        let metadata = SsTableMetadata::load(metadata_path);
+        let data_path = metadata.data_path();
+        let data_file = ReadOnlyDataFile::open(&data_path).expect("Can't create/open data file");
+        let index = SstableIndex::load(&metadata.index_path()).expect("Can't open index file");
+        let index = TableIndex::Map(index);
+        let bloom_filter = SstableBloomFilter::load(&metadata.bloom_filter_path())
+            .expect("Can't open bloom filter file");
+        let properties = TableProperties {
+            id: metadata.id,
+            level: metadata.level,
+            data_size: data_file.size()?,
+            ..TableProperties::default()
+        };
+        Ok(SsTableMeta {
+            blocks: BlockReader {
+                layout: DataLayout::Records,
+                path: data_path,
+                dictionary: None,
+                file_number: metadata.id,
+                cache: None,
+            },
+            metadata,
+            index,
+            bloom_filter,
+            properties,
+        })
+    }
+
+    fn load_single_file(path: &Path, cache: Option<Arc<BlockCache>>) -> io::Result<SsTableMeta> {
+        let metadata = SsTableMetadata::from_table_path(path)?;
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let footer = Footer::read_from(&mut file, path)?;
+        let mut blocks = BlockReader {
+            layout: DataLayout::from_version(footer.version),
+            path: path.to_path_buf(),
+            dictionary: None,
+            file_number: metadata.id,
+            cache,
+        };
+        let index_block = blocks.read_meta(&mut file, footer.index)?;
+        let filter_block = blocks.read_meta(&mut file, footer.filter)?;
+        let properties_block = blocks.read_meta(&mut file, footer.properties)?;
+        let properties_block: PropertiesBlock =
+            serde_json::from_slice(&properties_block).map_err(io::Error::from)?;
+        if let Some(handle) = properties_block.compression_dictionary {
+            blocks.dictionary = Some(Arc::new(blocks.read_meta(&mut file, handle)?.to_vec()));
+        }
+        let index = if footer.version > CHECKSUMMED_FORMAT_VERSION {
+            let top =
+                Block::new(index_block).map_err(|e| blocks.corrupted(footer.index.offset, e))?;
+            TableIndex::Blocks(BlockIndex::new(top, properties_block.partitioned_index))
+        } else {
+            TableIndex::Map(SstableIndex::from_bytes(&index_block)?)
+        };
+        let properties = TableProperties {
+            id: metadata.id,
+            level: metadata.level,
+            data_size: properties_block.data_size,
+            ..properties_block.properties
+        };
+        Ok(SsTableMeta {
+            metadata,
+            index,
+            bloom_filter: SstableBloomFilter::from_bytes(&filter_block)?,
+            properties,
+            blocks,
+        })
+    }
+
+    /// Moves the table to `level` by renaming its file into that level's
+    /// directory, which is all that records the level of a single-file
+    /// table. Legacy tables store their level in the metadata file and
+    /// can't be moved.
+    pub(crate) fn move_to(&mut self, level: u8) -> io::Result<()> {
+        if self.metadata.format == TableFormat::Legacy {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("legacy table {} can't be moved", self.metadata.id),
+            ));
+        }
+        let from = self.metadata.data_path();
+        let mut metadata = self.metadata.clone();
+        metadata.level = level;
+        let to = metadata.data_path();
+        fs::rename(&from, &to)?;
+        self.metadata = metadata;
+        self.properties.level = level;
+        self.blocks.path = to.clone();
+        fs_utils::sync_parent_dir(&to)?;
+        fs_utils::sync_parent_dir(&from)
+    }
+
+    /// Reads the whole table and checks it against its checksums: the CRC32C
+    /// of every block, or the SHA-256 checksums of older tables.
+    pub(crate) fn verify_checksums(&self) -> io::Result<()> {
+        if self.metadata.format == TableFormat::Legacy {
+            return Checksums::verify(&self.metadata);
+        }
+        let path = self.metadata.data_path();
+        let mut file = OpenOptions::new().read(true).open(&path)?;
+        let footer = Footer::read_from(&mut file, &path)?;
+        let index_block = self.blocks.read_meta(&mut file, footer.index)?;
+        self.blocks.read_meta(&mut file, footer.filter)?;
+        let properties_block = self.blocks.read_meta(&mut file, footer.properties)?;
+        let properties_block: PropertiesBlock =
+            serde_json::from_slice(&properties_block).map_err(io::Error::from)?;
+        if let Some(checksums) = properties_block.checksums {
+            return checksums.verify_table(&path, self.properties.data_size, &index_block);
+        }
+        // blocks are read from disk even if cached, so every one is checked
+        let data = ReadOnlyDataFile::from(file);
+        let read = |handle| self.blocks.read_uncached(&data, handle).map(Arc::new);
+        let mut handles = self.index.iter_from(None, self.properties.data_size);
+        while let Some(handle) = handles.next(&read)? {
+            for kv in BlockIter::new(read(handle)?) {
+                kv?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Value of `key`, sharing the buffer of the block it is in.
+    pub(crate) fn get(&self, data: &ReadOnlyDataFile, key: &ByteStr) -> io::Result<Option<Bytes>> {
+        if !self.properties.may_contain(key) || !self.bloom_filter.contains(key) {
+            return Ok(None);
+        }
+        let size_bytes = self.properties.data_size;
+        match &self.index {
+            TableIndex::Map(index) if self.blocks.layout == DataLayout::Records => {
+                let result = match index.get(key) {
+                    Some(pos) => data.read_record(*pos).map(|op| op.map(|p| p.0)),
+                    None => {
+                        let (start, end) = index.position_range(key, size_bytes);
+                        data.scan_range(key, start, end)
+                    }
+                }?;
+                Ok(result.map(|kv| Bytes::from(kv.value_owned())))
+            }
+            index => {
+                let read = |handle: BlockHandle| self.blocks.read(data, handle, true);
+                match index.find_block(key, size_bytes, &read)? {
+                    Some(handle) => read(handle)?.get(key),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Cursor over the entries with keys from `start` on.
+    pub(crate) fn cursor_from(&self, start: Option<&ByteStr>) -> TableCursor {
+        let size_bytes = self.properties.data_size;
+        let mut pos = 0;
+        let handles = match &self.index {
+            TableIndex::Map(index) if self.blocks.layout == DataLayout::Records => {
+                if let Some(start) = start {
+                    pos = index.position_range(start, size_bytes).0;
+                }
+                IndexIter::Handles(VecDeque::new())
+            }
+            index => index.iter_from(start, size_bytes),
+        };
+        TableCursor {
+            reader: self.blocks.clone(),
+            pos,
+            end: size_bytes,
+            handles,
+            block: None,
+            lower_bound: start.map(|start| start.to_vec()),
+        }
+    }
+}
+
+/// Position of a sequential scan over the data part of a table.
+pub(crate) struct TableCursor {
+    reader: BlockReader,
+    pos: u64,
+    end: u64,
+    /// Handles of the blocks not read yet.
+    handles: IndexIter,
+    block: Option<BlockIter>,
+    /// Entries before this key are skipped.
+    lower_bound: Option<ByteString>,
+}
+
+impl TableCursor {
+    pub(crate) fn next(&mut self, data: &ReadOnlyDataFile) -> io::Result<Option<KeyValuePair>> {
+        while let Some(kv) = self.next_entry(data)? {
+            match &self.lower_bound {
+                Some(bound) if kv.key_ref() < bound.as_slice() => continue,
+                _ => {
+                    self.lower_bound = None;
+                    return Ok(Some(kv));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_entry(&mut self, data: &ReadOnlyDataFile) -> io::Result<Option<KeyValuePair>> {
+        match self.reader.layout {
+            DataLayout::Records => {
+                if self.pos >= self.end {
+                    return Ok(None);
+                }
+                Ok(data.read_record(self.pos)?.map(|(kv_pair, len)| {
+                    self.pos += len;
+                    kv_pair
+                }))
+            }
+            _ => loop {
+                if let Some(kv) = self.block.as_mut().and_then(|block| block.next()) {
+                    return kv.map(Some);
+                }
+                let reader = &self.reader;
+                let read = |handle| reader.read(data, handle, false);
+                let handle = match self.handles.next(&read)? {
+                    Some(handle) => handle,
+                    None => return Ok(None),
+                };
+                self.block = Some(BlockIter::new(read(handle)?));
+            },
+        }
+    }
+}
+
+/// Writes a single-file table from records added in key order. The file is
+/// created under a temporary name and published by [`SsTableBuilder::finish`].
+pub(crate) struct SsTableBuilder {
+    metadata: SsTableMetadata,
+    file: WriteableDataFile,
+    index: IndexBuilder,
+    bloom_filter: SstableBloomFilter,
+    block: BlockBuilder,
+    compression: CompressionType,
+    dictionary: Option<Arc<Vec<u8>>>,
+    block_cache: Option<Arc<BlockCache>>,
+    properties: TableProperties,
+    pos: u64,
+}
+
+impl SsTableBuilder {
+    /// Writes go through the rate limiter of `options` at `priority`.
+    pub(crate) fn new(
+        options: &TableOptions,
+        level: u8,
+        expected_entries: usize,
+        priority: IoPriority,
+    ) -> io::Result<SsTableBuilder> {
+        let metadata = SsTableMetadata::new(options.base_path.clone(), level);
+        let properties = TableProperties {
+            id: metadata.id,
+            level,
+            entries: Some(0),
+            tombstones: Some(0),
+            sequence_range: Some((metadata.id, metadata.id)),
+            ..TableProperties::default()
+        };
+        let limiter = options
+            .rate_limiter
+            .as_ref()
+            .map(|limiter| (limiter.clone(), priority));
+        let file = WriteableDataFile::create(&metadata.data_path(), limiter)?;
+        let compression = options.compression.for_level(level);
+        let dictionary = match compression {
+            CompressionType::Zstd => options.compression.zstd_dictionary().cloned(),
+            _ => None,
+        };
+        Ok(SsTableBuilder {
+            metadata,
+            file,
+            index: IndexBuilder::new(),
+            bloom_filter: SstableBloomFilter::new(expected_entries.max(1)),
+            block: BlockBuilder::new(),
+            compression,
+            dictionary,
+            block_cache: options.block_cache.clone(),
+            properties,
+            pos: 0,
+        })
+    }
+
+    /// Sets the sequence range of the table, which defaults to its own id.
+    /// Compactions pass the range covered by their inputs.
+    pub(crate) fn set_sequence_range(&mut self, range: (u128, u128)) {
+        self.properties.sequence_range = Some(range);
+    }
+
+    pub(crate) fn add(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.block.add(key, value);
+        self.bloom_filter.insert(key);
+        let properties = &mut self.properties;
+        if properties.smallest_key.is_none() {
+            properties.smallest_key = Some(key.to_vec());
+        }
+        properties.largest_key = Some(key.to_vec());
+        *properties.entries.get_or_insert(0) += 1;
+        if value == [0] {
+            *properties.tombstones.get_or_insert(0) += 1;
+        }
+        if self.block.estimated_size() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// Size of the data written so far, including the pending block.
+    fn estimated_size(&self) -> u64 {
+        self.pos + self.block.estimated_size() as u64
+    }
+
+    /// Writes `block` with its trailer at the current position.
+    fn write_block(
+        &mut self,
+        mut block: Vec<u8>,
+        codec: CompressionType,
+    ) -> io::Result<BlockHandle> {
+        append_trailer(&mut block, codec);
+        self.file.write_all(&block)?;
+        let handle = BlockHandle {
+            offset: self.pos,
+            size: block.len() as u64,
+        };
+        self.pos += handle.size;
+        Ok(handle)
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        let dictionary = self.dictionary.as_ref().map(|d| d.as_slice());
+        let (codec, block) =
+            compression::compress(self.compression, self.block.finish(), dictionary)?;
+        let handle = self.write_block(block, codec)?;
+        // the block ends with the largest key added so far
+        let last_key = self.properties.largest_key.clone().unwrap_or_default();
+        self.index.add(last_key, handle)
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<SsTableMeta> {
+        debug_assert_eq!(self.metadata.format, TableFormat::SingleFile);
+        if !self.block.is_empty() {
+            self.flush_block()?;
+        }
+        self.properties.data_size = self.pos;
+        let compression_dictionary = match self.dictionary.clone() {
+            Some(dictionary) => Some(self.write_block(dictionary.to_vec(), CompressionType::None)?),
+            None => None,
+        };
+        let index_builder = mem::replace(&mut self.index, IndexBuilder::new());
+        let (index_handle, index) =
+            index_builder.finish(|block| self.write_block(block, CompressionType::None))?;
+        let properties_block = PropertiesBlock {
+            data_size: self.properties.data_size,
+            properties: self.properties.clone(),
+            checksums: None,
+            compression_dictionary,
+            partitioned_index: index.is_partitioned(),
+        };
+        let properties_block = serde_json::to_vec(&properties_block).map_err(io::Error::from)?;
+        let footer = Footer {
+            index: index_handle,
+            filter: self.write_block(self.bloom_filter.to_bytes()?, CompressionType::None)?,
+            properties: self.write_block(properties_block, CompressionType::None)?,
+            version: FORMAT_VERSION,
+        };
+        footer.write_to(&mut self.file)?;
+        self.file.commit()?;
+        let path = self.metadata.data_path();
+        Ok(SsTableMeta {
+            blocks: BlockReader {
+                layout: DataLayout::ChecksummedBlocks,
+                path,
+                dictionary: self.dictionary,
+                file_number: self.metadata.id,
+                cache: self.block_cache,
+            },
+            metadata: self.metadata,
+            index: TableIndex::Blocks(index),
+            bloom_filter: self.bloom_filter,
+            properties: self.properties,
+        })
+    }
+}
+
+/// Writes the output of a compaction, starting a new table whenever the
+/// current one reaches `TableOptions::target_file_size`.
+pub(crate) struct SplittingTableBuilder<'a> {
+    options: &'a TableOptions,
+    level: u8,
+    expected_entries: usize,
+    sequence_range: Option<(u128, u128)>,
+    current: Option<SsTableBuilder>,
+    finished: Vec<SsTableMeta>,
+}
+
+impl<'a> SplittingTableBuilder<'a> {
+    pub(crate) fn new(
+        options: &'a TableOptions,
+        level: u8,
+        expected_entries: usize,
+        sequence_range: Option<(u128, u128)>,
+    ) -> SplittingTableBuilder<'a> {
+        SplittingTableBuilder {
+            options,
+            level,
+            expected_entries,
+            sequence_range,
+            current: None,
+            finished: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        let builder = match &mut self.current {
+            Some(builder) => builder,
+            None => {
+                let mut builder = SsTableBuilder::new(
+                    self.options,
+                    self.level,
+                    self.expected_entries,
+                    IoPriority::Low,
+                )?;
+                if let Some(range) = self.sequence_range {
+                    builder.set_sequence_range(range);
+                }
+                self.current.insert(builder)
+            }
+        };
+        builder.add(key, value)?;
+        if builder.estimated_size() >= self.options.target_file_size {
+            if let Some(builder) = self.current.take() {
+                self.finished.push(builder.finish()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the last table. No table is written for empty input.
+    pub(crate) fn finish(mut self) -> io::Result<Vec<SsTableMeta>> {
+        if let Some(builder) = self.current.take() {
+            self.finished.push(builder.finish()?);
+        }
+        Ok(self.finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use std::{env, fs};
+
+    use bytes::Bytes;
+
+    use crate::block::BlockBuilder;
+    use crate::compression::CompressionType;
+    use crate::datafile::ReadOnlyDataFile;
+    use crate::sstable_bloom_filter::SstableBloomFilter;
+    use crate::sstable_format::{
+        append_trailer, BlockHandle, Footer, PropertiesBlock, SsTableMeta, TableProperties,
+        CHECKSUMMED_FORMAT_VERSION, FORMAT_VERSION, TABLE_MAGIC,
+    };
+    use crate::sstable_index::SstableIndex;
+
+    #[test]
+    fn footer_round_trip() {
+        let footer = Footer {
+            index: BlockHandle {
+                offset: 100,
+                size: 20,
+            },
+            filter: BlockHandle {
+                offset: 120,
+                size: 30,
+            },
+            properties: BlockHandle {
+                offset: 150,
+                size: 40,
+            },
+            version: FORMAT_VERSION,
+        };
+        let mut buf = vec![0u8; 190];
+        footer.write_to(&mut buf).unwrap();
+        let mut cursor = Cursor::new(buf);
+        let path = std::path::Path::new("table_1.sst");
+        assert_eq!(footer, Footer::read_from(&mut cursor, path).unwrap());
+    }
+
+    #[test]
+    fn footer_with_bad_magic_is_rejected() {
+        let mut buf = vec![0u8; 64];
+        let len = buf.len();
+        buf[len - 8..].copy_from_slice(&(TABLE_MAGIC + 1).to_le_bytes());
+        let mut cursor = Cursor::new(buf);
+        let path = std::path::Path::new("table_1.sst");
+        assert!(Footer::read_from(&mut cursor, path).is_err());
+    }
+
+    /// Appends `block` with its trailer to `file` and returns its handle.
+    fn write_block(file: &mut Vec<u8>, mut block: Vec<u8>) -> BlockHandle {
+        append_trailer(&mut block, CompressionType::None);
+        let offset = file.len() as u64;
+        file.write_all(&block).unwrap();
+        BlockHandle::between(offset, file.len() as u64)
+    }
+
+    #[test]
+    fn version_4_table_with_map_index() {
+        let mut dir = env::temp_dir();
+        dir.push("sstable_format_test");
+        dir.push("level-0");
+        fs::remove_dir_all(&dir).unwrap_or(());
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = Vec::new();
+        let mut index = SstableIndex::new();
+        let mut bloom_filter = SstableBloomFilter::new(300);
+        for first in [0, 100, 200] {
+            let mut block = BlockBuilder::new();
+            for i in first..first + 100 {
+                let key = format!("key_{:03}", i).into_bytes();
+                block.add(&key, format!("value_{}", i).as_bytes());
+                bloom_filter.insert(&key);
+            }
+            let handle = write_block(&mut file, block.finish());
+            index.insert(format!("key_{:03}", first).into_bytes(), handle.offset);
+        }
+        let properties = PropertiesBlock {
+            data_size: file.len() as u64,
+            properties: TableProperties::default(),
+            checksums: None,
+            compression_dictionary: None,
+            partitioned_index: false,
+        };
+        let footer = Footer {
+            index: write_block(&mut file, index.to_bytes().unwrap()),
+            filter: write_block(&mut file, bloom_filter.to_bytes().unwrap()),
+            properties: write_block(&mut file, serde_json::to_vec(&properties).unwrap()),
+            version: CHECKSUMMED_FORMAT_VERSION,
+        };
+        footer.write_to(&mut file).unwrap();
+        let path = dir.join("table_1.sst");
+        fs::write(&path, file).unwrap();
+
+        let meta = SsTableMeta::load(&path, None).unwrap();
+        let data = ReadOnlyDataFile::open(&path).unwrap();
+        for i in 0..300 {
+            let key = format!("key_{:03}", i).into_bytes();
+            let val = meta.get(&data, &key).unwrap();
+            assert_eq!(Some(Bytes::from(format!("value_{}", i))), val);
+        }
+        let mut cursor = meta.cursor_from(Some(b"key_150"));
+        let mut entries = 0;
+        while cursor.next(&data).unwrap().is_some() {
+            entries += 1;
+        }
+        assert_eq!(150, entries);
+        meta.verify_checksums().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,16 +1,41 @@
+//! Index of a table, mapping keys to the data blocks that hold them.
+//!
+//! Tables of format version 5 store the index as blocks in the format of
+//! `block`: each entry maps the last key of a data block to its encoded
+//! `BlockHandle`, so the block that can hold a key is the first entry with a
+//! key not less than it. Small tables have a single index block. Once the
+//! entries outgrow `INDEX_PARTITION_SIZE` they are split into partitions,
+//! and a top-level block maps the last key of every partition to its handle.
+//! Only the top-level block is kept in memory; partitions are read through
+//! the block cache like data blocks.
+//!
+//! Older tables store a serialized `BTreeMap` instead, which is loaded whole.
+
+use crate::block::{Block, BlockBuilder, BlockIter};
+use crate::sstable_format::BlockHandle;
 use crate::{ByteStr, ByteString};
 use std::collections::btree_map::Range;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::OpenOptions;
 use std::io::ErrorKind;
+use std::mem;
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::Arc;
 use tokio::io;
 
+const INDEX_PARTITION_SIZE: usize = 4096;
+
+/// Reads the index partition or data block at a handle.
+pub(crate) type ReadBlock<'a> = &'a dyn Fn(BlockHandle) -> io::Result<Arc<Block>>;
+
+/// Index of legacy tables and of tables before format version 5.
 pub(crate) struct SstableIndex {
     map: BTreeMap<ByteString, u64>,
 }
 
 impl SstableIndex {
+    #[cfg(test)]
     pub(crate) fn new() -> SstableIndex {
         SstableIndex {
             map: BTreeMap::new(),
@@ -23,10 +48,22 @@ impl SstableIndex {
             .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
         Ok(SstableIndex { map: index })
     }
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<SstableIndex> {
+        let index: BTreeMap<ByteString, u64> =
+            bincode::deserialize(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(SstableIndex { map: index })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        bincode::serialize(&self.map).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
     pub(crate) fn get(&self, key: &ByteStr) -> Option<&u64> {
         self.map.get(key)
     }
 
+    #[cfg(test)]
     pub(crate) fn insert(&mut self, key: ByteString, val: u64) {
         self.map.insert(key, val);
     }
@@ -39,9 +76,330 @@ impl SstableIndex {
         (start, end)
     }
 
-    pub(crate) fn write_to_file(&self, path: &Path) -> io::Result<()> {
-        let index_file = OpenOptions::new().write(true).create(true).open(path)?;
-        bincode::serialize_into(index_file, &self.map)
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    /// For block-based tables, where the index maps the first key of every
+    /// block to its offset: the byte range of the only block that can hold
+    /// `key`, or `None` if `key` sorts before the first block.
+    pub(crate) fn block_range(&self, key: &ByteStr, size_bytes: u64) -> Option<(u64, u64)> {
+        let start = *self
+            .map
+            .range::<ByteStr, _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()?
+            .1;
+        let end = self
+            .map
+            .range::<ByteStr, _>((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+            .map(|e| *e.1)
+            .unwrap_or(size_bytes);
+        Some((start, end))
+    }
+
+    pub(crate) fn offsets(&self) -> impl Iterator<Item = u64> + '_ {
+        self.map.values().copied()
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &ByteStr> + '_ {
+        self.map.keys().map(|key| key.as_slice())
+    }
+}
+
+/// Index of an open table.
+pub(crate) enum TableIndex {
+    Map(SstableIndex),
+    Blocks(BlockIndex),
+}
+
+impl TableIndex {
+    /// Handle of the only data block that can hold `key`, if any. The data
+    /// part of the table is `size_bytes` long.
+    pub(crate) fn find_block(
+        &self,
+        key: &ByteStr,
+        size_bytes: u64,
+        read: ReadBlock,
+    ) -> io::Result<Option<BlockHandle>> {
+        match self {
+            TableIndex::Map(index) => Ok(index
+                .block_range(key, size_bytes)
+                .map(|(start, end)| BlockHandle::between(start, end))),
+            TableIndex::Blocks(index) => index.find_block(key, read),
+        }
+    }
+
+    /// Iterates over the handles of the data blocks, from the one that can
+    /// hold `start` on.
+    pub(crate) fn iter_from(&self, start: Option<&ByteStr>, size_bytes: u64) -> IndexIter {
+        match self {
+            TableIndex::Map(index) => {
+                let first = start
+                    .and_then(|start| index.block_range(start, size_bytes))
+                    .map_or(0, |range| range.0);
+                let offsets: Vec<u64> = index.offsets().filter(|o| *o >= first).collect();
+                let ends = offsets.iter().skip(1).copied().chain([size_bytes]);
+                let handles = offsets
+                    .iter()
+                    .zip(ends)
+                    .map(|(start, end)| BlockHandle::between(*start, end))
+                    .collect();
+                IndexIter::Handles(handles)
+            }
+            TableIndex::Blocks(index) => IndexIter::Blocks(BlockIndexIter {
+                top: BlockIter::new(index.top.clone()),
+                partition: None,
+                partitioned: index.partitioned,
+                start: start.map(|start| start.to_vec()),
+                started: false,
+            }),
+        }
+    }
+
+    /// Keys that split the table into parts of about the same size: the keys
+    /// of the blocks, or of the index partitions of a partitioned index.
+    pub(crate) fn split_keys(&self) -> io::Result<Vec<ByteString>> {
+        match self {
+            TableIndex::Map(index) => Ok(index.keys().map(|key| key.to_vec()).collect()),
+            TableIndex::Blocks(index) => BlockIter::new(index.top.clone())
+                .map(|kv| kv.map(|kv| kv.key_cloned()))
+                .collect(),
+        }
+    }
+}
+
+/// Index stored as blocks. Holds the top-level block, which is the whole
+/// index unless it is partitioned.
+pub(crate) struct BlockIndex {
+    top: Arc<Block>,
+    partitioned: bool,
+}
+
+impl BlockIndex {
+    pub(crate) fn new(top: Block, partitioned: bool) -> BlockIndex {
+        BlockIndex {
+            top: Arc::new(top),
+            partitioned,
+        }
+    }
+
+    pub(crate) fn is_partitioned(&self) -> bool {
+        self.partitioned
+    }
+
+    fn find_block(&self, key: &ByteStr, read: ReadBlock) -> io::Result<Option<BlockHandle>> {
+        let mut top = BlockIter::new(self.top.clone());
+        top.seek(key)?;
+        let handle = match next_handle(&mut top)? {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        if !self.partitioned {
+            return Ok(Some(handle));
+        }
+        let mut partition = BlockIter::new(read(handle)?);
+        partition.seek(key)?;
+        next_handle(&mut partition)
+    }
+}
+
+/// Handles of the data blocks of a table, in key order.
+pub(crate) enum IndexIter {
+    Handles(VecDeque<BlockHandle>),
+    Blocks(BlockIndexIter),
+}
+
+impl IndexIter {
+    /// Reads index partitions with `read` as they are reached.
+    pub(crate) fn next(&mut self, read: ReadBlock) -> io::Result<Option<BlockHandle>> {
+        match self {
+            IndexIter::Handles(handles) => Ok(handles.pop_front()),
+            IndexIter::Blocks(iter) => iter.next(read),
+        }
+    }
+}
+
+pub(crate) struct BlockIndexIter {
+    top: BlockIter,
+    partition: Option<BlockIter>,
+    partitioned: bool,
+    /// Key the iteration starts at, until the first block is found.
+    start: Option<ByteString>,
+    started: bool,
+}
+
+impl BlockIndexIter {
+    fn next(&mut self, read: ReadBlock) -> io::Result<Option<BlockHandle>> {
+        if !self.started {
+            self.started = true;
+            if let Some(start) = &self.start {
+                self.top.seek(start)?;
+            }
+            if !self.partitioned {
+                self.start = None;
+            }
+        }
+        loop {
+            if !self.partitioned {
+                return next_handle(&mut self.top);
+            }
+            if let Some(partition) = &mut self.partition {
+                if let Some(handle) = next_handle(partition)? {
+                    return Ok(Some(handle));
+                }
+            }
+            let handle = match next_handle(&mut self.top)? {
+                Some(handle) => handle,
+                None => return Ok(None),
+            };
+            let mut partition = BlockIter::new(read(handle)?);
+            if let Some(start) = self.start.take() {
+                partition.seek(&start)?;
+            }
+            self.partition = Some(partition);
+        }
+    }
+}
+
+fn next_handle(iter: &mut BlockIter) -> io::Result<Option<BlockHandle>> {
+    match iter.next().transpose()? {
+        Some(kv) => BlockHandle::read_from(&mut kv.value_ref()).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Collects the index entries of a table while its data blocks are written.
+pub(crate) struct IndexBuilder {
+    partition: BlockBuilder,
+    last_key: ByteString,
+    /// Finished partitions with their last key.
+    partitions: Vec<(ByteString, Vec<u8>)>,
+}
+
+impl IndexBuilder {
+    pub(crate) fn new() -> IndexBuilder {
+        IndexBuilder {
+            partition: BlockBuilder::new(),
+            last_key: ByteString::new(),
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Adds the data block at `handle`, whose last key is `last_key`.
+    pub(crate) fn add(&mut self, last_key: ByteString, handle: BlockHandle) -> io::Result<()> {
+        let mut value = Vec::with_capacity(BlockHandle::ENCODED_SIZE as usize);
+        handle.write_to(&mut value)?;
+        self.partition.add(&last_key, &value);
+        self.last_key = last_key;
+        if self.partition.estimated_size() >= INDEX_PARTITION_SIZE {
+            self.finish_partition();
+        }
+        Ok(())
+    }
+
+    fn finish_partition(&mut self) {
+        let last_key = mem::take(&mut self.last_key);
+        self.partitions.push((last_key, self.partition.finish()));
+    }
+
+    /// Writes the index with `write_block`, partitions first, and returns
+    /// the handle of its top-level block.
+    pub(crate) fn finish(
+        mut self,
+        mut write_block: impl FnMut(Vec<u8>) -> io::Result<BlockHandle>,
+    ) -> io::Result<(BlockHandle, BlockIndex)> {
+        if !self.partition.is_empty() || self.partitions.is_empty() {
+            self.finish_partition();
+        }
+        let partitioned = self.partitions.len() > 1;
+        let top = if partitioned {
+            let mut top = BlockBuilder::new();
+            for (last_key, partition) in self.partitions {
+                let mut value = Vec::with_capacity(BlockHandle::ENCODED_SIZE as usize);
+                write_block(partition)?.write_to(&mut value)?;
+                top.add(&last_key, &value);
+            }
+            top.finish()
+        } else {
+            self.partitions.remove(0).1
+        };
+        let handle = write_block(top.clone())?;
+        Ok((
+            handle,
+            BlockIndex::new(Block::new(top.into())?, partitioned),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use crate::block::Block;
+    use crate::sstable_format::BlockHandle;
+    use crate::sstable_index::{IndexBuilder, TableIndex};
+
+    /// Builds an index of `blocks` data blocks of 100 bytes, with the index
+    /// blocks in an in-memory file.
+    fn build_index(blocks: u64) -> (TableIndex, Vec<u8>) {
+        let mut builder = IndexBuilder::new();
+        for i in 0..blocks {
+            let key = format!("key_{:05}", i * 10).into_bytes();
+            builder
+                .add(key, BlockHandle::between(i * 100, (i + 1) * 100))
+                .unwrap();
+        }
+        let file = RefCell::new(vec![0u8; blocks as usize * 100]);
+        let (_, index) = builder
+            .finish(|block| {
+                let mut file = file.borrow_mut();
+                let start = file.len() as u64;
+                file.extend_from_slice(&block);
+                Ok(BlockHandle::between(start, file.len() as u64))
+            })
+            .unwrap();
+        (TableIndex::Blocks(index), file.into_inner())
+    }
+
+    #[test]
+    fn finds_blocks() {
+        for blocks in [10, 1000] {
+            let (index, file) = build_index(blocks);
+            if let TableIndex::Blocks(index) = &index {
+                assert_eq!(blocks == 1000, index.is_partitioned());
+            }
+            let reads = RefCell::new(0);
+            let read = |handle: BlockHandle| {
+                *reads.borrow_mut() += 1;
+                let block =
+                    Bytes::copy_from_slice(&file[handle.offset as usize..][..handle.size as usize]);
+                Ok(Arc::new(Block::new(block)?))
+            };
+            let find = |key: &str| {
+                index
+                    .find_block(key.as_bytes(), 0, &read)
+                    .unwrap()
+                    .map(|handle| handle.offset)
+            };
+            // a block holds the keys after the last key of the one before
+            assert_eq!(Some(0), find("a"));
+            assert_eq!(Some(0), find("key_00000"));
+            assert_eq!(Some(100), find("key_00000a"));
+            assert_eq!(
+                Some((blocks - 1) * 100),
+                find(&format!("key_{:05}", (blocks - 1) * 10))
+            );
+            assert_eq!(None, find("zzz"));
+
+            let mut iter = index.iter_from(Some(b"key_00015"), 0);
+            let mut offsets = Vec::new();
+            while let Some(handle) = iter.next(&read).unwrap() {
+                offsets.push(handle.offset);
+            }
+            let expected: Vec<u64> = (2..blocks).map(|i| i * 100).collect();
+            assert_eq!(expected, offsets);
+            // only partitions are read, the top level is in memory
+            assert_eq!(blocks == 1000, *reads.borrow() > 0);
+        }
     }
 }
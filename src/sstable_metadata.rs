@@ -1,9 +1,50 @@
+use crate::fs_utils;
+use log::info;
+use parking_lot::{const_mutex, Mutex};
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
 use std::io;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+const TABLE_EXTENSION: &str = ".sst";
+
+/// Largest table id handed out or loaded so far.
+static LAST_TABLE_ID: Mutex<u128> = const_mutex(0);
+
+/// Table ids are millisecond timestamps, bumped past the last id when several
+/// tables are created within the same millisecond. Newer tables always get
+/// larger ids, which is what orders data between tables.
+fn next_table_id() -> u128 {
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let mut last = LAST_TABLE_ID.lock();
+    *last = since_the_epoch.as_millis().max(*last + 1);
+    *last
+}
+
+/// Makes sure ids of new tables are larger than `id` of an existing one.
+fn observe_table_id(id: u128) {
+    let mut last = LAST_TABLE_ID.lock();
+    *last = id.max(*last);
+}
+
+/// On-disk layout of a table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum TableFormat {
+    /// Separate data, index, bloom filter, checksum and metadata files.
+    #[default]
+    Legacy,
+    /// One `table_<id>.sst` file, see `sstable_format`.
+    SingleFile,
+}
+
+/// Describes where a table lives. Only legacy tables persist this struct (as
+/// their metadata file); for single-file tables it is derived from the path
+/// and the `*_filename` fields other than `data_filename` are empty.
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct SsTableMetadata {
     pub(crate) base_path: String,
@@ -14,32 +55,76 @@ pub(crate) struct SsTableMetadata {
     pub(crate) data_filename: String,
     pub(crate) index_filename: String,
     pub(crate) bloom_filter_filename: String,
+    #[serde(skip)]
+    pub(crate) format: TableFormat,
 }
 
 impl SsTableMetadata {
     pub fn new(base_path: String, level: u8) -> SsTableMetadata {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-
-        let timestamp = since_the_epoch.as_millis();
-        let metadata_filename = format!("metadata_{}.db", timestamp);
-        let data_filename = format!("data_{}.db", timestamp);
-        let index_filename = format!("index_{}.db", timestamp);
-        let checksum_filename = format!("checksum_{}.db", timestamp);
-        let bloom_filter_filename = format!("bloom_{}.db", timestamp);
+        let timestamp = next_table_id();
         SsTableMetadata {
             base_path,
             level,
             id: timestamp,
-            metadata_filename,
-            data_filename,
-            index_filename,
-            checksum_filename,
-            bloom_filter_filename,
+            metadata_filename: String::new(),
+            data_filename: format!("table_{}{}", timestamp, TABLE_EXTENSION),
+            index_filename: String::new(),
+            checksum_filename: String::new(),
+            bloom_filter_filename: String::new(),
+            format: TableFormat::SingleFile,
         }
     }
+
+    /// Reconstructs the metadata of a single-file table from
+    /// `<base_path>/level-<level>/table_<id>.sst`.
+    pub(crate) fn from_table_path(path: &Path) -> io::Result<SsTableMetadata> {
+        let invalid_path = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not an sstable path", path),
+            )
+        };
+        let data_filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(invalid_path)?;
+        let id = data_filename
+            .strip_prefix("table_")
+            .and_then(|name| name.strip_suffix(TABLE_EXTENSION))
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(invalid_path)?;
+        let level_path = path.parent().ok_or_else(invalid_path)?;
+        let level = level_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("level-"))
+            .and_then(|level| level.parse().ok())
+            .ok_or_else(invalid_path)?;
+        let base_path = level_path
+            .parent()
+            .and_then(|base| base.to_str())
+            .ok_or_else(invalid_path)?;
+        observe_table_id(id);
+        Ok(SsTableMetadata {
+            base_path: base_path.to_string(),
+            id,
+            level,
+            metadata_filename: String::new(),
+            data_filename: data_filename.to_string(),
+            index_filename: String::new(),
+            checksum_filename: String::new(),
+            bloom_filter_filename: String::new(),
+            format: TableFormat::SingleFile,
+        })
+    }
+
+    /// Whether `name` is the file a table is loaded from: the metadata file of
+    /// a legacy table or a single-file table.
+    pub(crate) fn is_table_file(name: &str) -> bool {
+        !fs_utils::is_temp_file(name)
+            && (name.starts_with("metadata_") || name.ends_with(TABLE_EXTENSION))
+    }
+
     fn construct_path(&self, filename: &str) -> PathBuf {
         let mut path = PathBuf::from(&self.base_path);
         path.push(format!("level-{}", self.level));
@@ -47,6 +132,14 @@ impl SsTableMetadata {
         path
     }
 
+    /// Path `SsTable::load` expects for this table.
+    pub(crate) fn table_path(&self) -> PathBuf {
+        match self.format {
+            TableFormat::Legacy => self.metadata_path(),
+            TableFormat::SingleFile => self.data_path(),
+        }
+    }
+
     pub(crate) fn data_path(&self) -> PathBuf {
         self.construct_path(&self.data_filename)
     }
@@ -72,15 +165,54 @@ impl SsTableMetadata {
             .read(true)
             .open(metadata_path)
             .expect("Can't open metadata file");
-        serde_json::from_reader(metadata_file)
-            .expect("Can't read metadata file, file with unknown format")
+        let metadata: SsTableMetadata = serde_json::from_reader(metadata_file)
+            .expect("Can't read metadata file, file with unknown format");
+        observe_table_id(metadata.id);
+        metadata
     }
 
-    pub(crate) fn write_to_file(&self) -> io::Result<()> {
-        let metadata_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&self.metadata_path())?;
-        serde_json::to_writer(metadata_file, self).map_err(io::Error::from)
+    /// Deletes every file belonging to the table. The file the table is
+    /// loaded from goes first, so a crash halfway leaves only orphans.
+    pub(crate) fn remove_files(&self) -> io::Result<()> {
+        match self.format {
+            TableFormat::Legacy => {
+                fs::remove_file(self.metadata_path())?;
+                fs::remove_file(self.bloom_filter_path())?;
+                fs::remove_file(self.index_path())?;
+                fs::remove_file(self.checksum_path())?;
+                fs::remove_file(self.data_path())
+            }
+            TableFormat::SingleFile => fs::remove_file(self.data_path()),
+        }
+    }
+
+    /// Removes temporary files and files of tables whose metadata was never
+    /// written, i.e. leftovers of a flush or compaction interrupted by a crash.
+    pub(crate) fn remove_orphans(level_path: &Path) -> io::Result<()> {
+        fs_utils::remove_temp_files(level_path)?;
+        let mut committed = HashSet::new();
+        let mut table_files = Vec::new();
+        for entry in fs::read_dir(level_path)? {
+            let entry = entry?;
+            if let Some((prefix, id)) = entry.file_name().to_str().and_then(Self::parse_filename) {
+                if prefix == "metadata" {
+                    committed.insert(id);
+                } else {
+                    table_files.push((id, entry.path()));
+                }
+            }
+        }
+        for (id, path) in table_files {
+            if !committed.contains(&id) {
+                info!("Removing orphaned sstable file {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        fs_utils::sync_dir(level_path)
+    }
+
+    fn parse_filename(name: &str) -> Option<(&str, u128)> {
+        let (prefix, id) = name.strip_suffix(".db")?.rsplit_once('_')?;
+        id.parse().ok().map(|id| (prefix, id))
     }
 }
@@ -1,20 +1,28 @@
 use std::convert::TryFrom;
 use std::fs::File;
+use std::io::ErrorKind;
 use std::path::PathBuf;
-use std::{fs, io};
+use std::{fs, io, mem};
 
+use bytes::Bytes;
 use log::debug;
 
+use crate::compaction::{self, Compaction, CompactionKind, CompactionStrategy};
 use crate::config::Config;
 use crate::memtable::MemTable;
+use crate::merge_iterator::{self, InternalIterator, MergingIterator};
+use crate::sstable_format::{TableOptions, TableProperties};
+use crate::sstable_metadata::SsTableMetadata;
 use crate::sync::sstable::SsTable;
 use crate::wal::CommandLog;
-use crate::{ByteStr, ByteString};
+use crate::{ByteStr, ByteString, KeyValuePair};
 
 const SSTABLE_MAX_LEVEL: usize = 5;
 
 pub struct LsmStorage {
     config: Config,
+    table_options: TableOptions,
+    compaction: Box<dyn CompactionStrategy>,
     wal: CommandLog<File>,
     memtable: MemTable,
     sstables: Vec<Vec<SsTable>>,
@@ -22,12 +30,14 @@ pub struct LsmStorage {
 
 impl LsmStorage {
     pub fn load(config: Config) -> io::Result<LsmStorage> {
+        let table_options = TableOptions::from_config(&config)?;
         let path = PathBuf::from(&config.base_path);
         let mut levels = Vec::with_capacity(SSTABLE_MAX_LEVEL);
         for i in 0..SSTABLE_MAX_LEVEL {
             let mut level_path = path.clone();
             level_path.push(format!("level-{}", i));
             fs::create_dir_all(&level_path)?;
+            SsTableMetadata::remove_orphans(&level_path)?;
 
             let mut tables: Vec<SsTable> = Vec::new();
             let paths = fs::read_dir(level_path)?;
@@ -35,8 +45,9 @@ impl LsmStorage {
             for path in paths {
                 let path = path.expect("valid path in directory");
                 if let Some(name) = path.file_name().to_str() {
-                    if name.contains("metadata") {
-                        let sstable = SsTable::load(&path.path())?;
+                    if SsTableMetadata::is_table_file(name) {
+                        let sstable =
+                            SsTable::load(&path.path(), table_options.block_cache.clone())?;
                         tables.push(sstable);
                     }
                 }
@@ -46,9 +57,11 @@ impl LsmStorage {
         }
         let wal_path = LsmStorage::wal_path(&config.base_path);
         let mut command_log = CommandLog::new(wal_path)?;
-        let memtable =
-            MemTable::from_log(&mut command_log).expect("Can't restore memtable from a log");
+        let memtable = MemTable::from_log(&mut command_log, config.memtable_type)
+            .expect("Can't restore memtable from a log");
         Ok(LsmStorage {
+            table_options,
+            compaction: compaction::strategy_from_config(&config),
             config,
             wal: command_log,
             memtable,
@@ -62,15 +75,15 @@ impl LsmStorage {
             .insert(&key, &value)
             .expect("Can't write command to WAL log");
         self.memtable.insert(key, value);
-        if self.memtable.size_in_bytes() >= self.config.memtable_limit_bytes {
+        if self.memtable.entries_memory_usage() >= self.config.memtable_limit_bytes {
             debug!("Memtable is too big, creating new sstable");
-            let sstable: SsTable = SsTable::from_memtable(&self.config.base_path, &self.memtable)
+            let sstable: SsTable = SsTable::from_memtable(&self.table_options, &self.memtable)
                 .expect("Can't create new sstable");
             self.wal.close().expect("Can't remove old wal log");
             self.sstables[0].push(sstable);
             self.wal = CommandLog::new(LsmStorage::wal_path(&self.config.base_path))
                 .expect("Can't create WAL file");
-            self.memtable = MemTable::new();
+            self.memtable = MemTable::with_type(self.config.memtable_type);
             self.compact()?;
         }
 
@@ -84,9 +97,15 @@ impl LsmStorage {
     }
 
     pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        Ok(self.get_pinned(key)?.map(|val| val.to_vec()))
+    }
+
+    /// Like `get`, but the value shares the memory of the memtable or the
+    /// cached block it was found in instead of being copied.
+    pub fn get_pinned(&mut self, key: &ByteStr) -> io::Result<Option<Bytes>> {
         match self.get_internal(key) {
             Ok(Some(val)) => {
-                if val == vec![0] {
+                if val.as_ref() == [0] {
                     Ok(None)
                 } else {
                     Ok(Some(val))
@@ -96,12 +115,12 @@ impl LsmStorage {
         }
     }
 
-    fn get_internal(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+    fn get_internal(&mut self, key: &ByteStr) -> io::Result<Option<Bytes>> {
         // let key_owned = key.to_vec();
-        match self.memtable.get(key) {
+        match self.memtable.get_pinned(key) {
             Some(val) => {
                 debug!("Key: {:?} found in memtable", key);
-                return Ok(Some(val.to_owned()));
+                return Ok(Some(val));
             }
             None => {
                 for i in 0..SSTABLE_MAX_LEVEL {
@@ -138,35 +157,178 @@ impl LsmStorage {
         Ok(())
     }
 
+    /// Entries with keys from `start` to `end` (both inclusive, `None` for
+    /// unbounded), in key order.
+    pub fn scan(
+        &mut self,
+        start: Option<&ByteStr>,
+        end: Option<&ByteStr>,
+    ) -> io::Result<Vec<KeyValuePair>> {
+        let mut sources: Vec<Box<dyn InternalIterator>> =
+            vec![Box::new(self.memtable.iter_from(start))];
+        for level in self.sstables.iter_mut() {
+            let properties: Vec<&TableProperties> =
+                level.iter().map(|table| table.properties()).collect();
+            let key_order = merge_iterator::key_order(&properties);
+            let tables = level
+                .iter_mut()
+                .map(|table| Box::new(table.iter_from(start)) as Box<dyn InternalIterator>)
+                .collect();
+            sources.extend(merge_iterator::level_sources(key_order, tables));
+        }
+        let mut entries = merge_iterator::collect_range(&mut MergingIterator::new(sources)?, end)?;
+        entries.retain(|kv| kv.value_ref() != [0]);
+        Ok(entries)
+    }
+
+    /// Properties of every table, from level 0 down, oldest first within a
+    /// level.
+    pub fn table_properties(&self) -> Vec<TableProperties> {
+        self.sstables
+            .iter()
+            .flatten()
+            .map(|sstable| sstable.properties().clone())
+            .collect()
+    }
+
+    /// Reads every table and checks it against its checksums. Blocks are
+    /// otherwise only checked when a lookup or scan reads them.
+    pub fn verify_checksums(&self) -> io::Result<()> {
+        for level in &self.sstables {
+            for sstable in level {
+                sstable.verify_checksums()?;
+            }
+        }
+        Ok(())
+    }
+
     fn compact(&mut self) -> io::Result<()> {
-        for i in 0..SSTABLE_MAX_LEVEL - 1 {
-            if self.sstables[i].len() >= self.config.sstable_level_limit {
-                let new_sstable = SsTable::merge_compact(
-                    &mut self.sstables[i],
-                    u8::try_from(i + 1).unwrap(),
-                    &self.config.base_path,
-                )?;
-                self.sstables[i + 1].push(new_sstable);
-                for table in &self.sstables[i] {
+        while self.compact_once(|compaction, levels| compaction.pick(levels))? {}
+        Ok(())
+    }
+
+    /// Compacts the tables overlapping the key range from `start` to `end`
+    /// (both inclusive, `None` for unbounded) down into `target_level`. Each
+    /// level above it is merged into the next one in turn, and tables of
+    /// `target_level` and deeper are only rewritten where they overlap.
+    pub fn compact_range(
+        &mut self,
+        start: Option<&ByteStr>,
+        end: Option<&ByteStr>,
+        target_level: usize,
+    ) -> io::Result<()> {
+        if target_level >= SSTABLE_MAX_LEVEL {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("there is no level {}", target_level),
+            ));
+        }
+        for level in 0..target_level {
+            self.compact_once(|_, levels| compaction::pick_range(levels, level, start, end))?;
+        }
+        Ok(())
+    }
+
+    /// Moves `tables` to `level`, see `CompactionKind::Move`. A table that
+    /// fails to move stays where it was.
+    fn move_tables(&mut self, tables: Vec<SsTable>, level: usize) -> io::Result<()> {
+        let mut result = Ok(());
+        for mut table in tables {
+            if let Err(err) = table.move_to(u8::try_from(level).unwrap()) {
+                result = result.and(Err(err));
+            }
+            self.sstables[table.properties().level as usize].push(table);
+        }
+        for level in &mut self.sstables {
+            level.sort();
+        }
+        result
+    }
+
+    /// Runs the compaction `pick` chooses, if any. Returns whether it ran one.
+    fn compact_once<F>(&mut self, pick: F) -> io::Result<bool>
+    where
+        F: for<'a> FnOnce(
+            &dyn CompactionStrategy,
+            &[Vec<&'a TableProperties>],
+        ) -> Option<Compaction>,
+    {
+        let levels: Vec<Vec<&TableProperties>> = self
+            .sstables
+            .iter()
+            .map(|level| level.iter().map(|table| table.properties()).collect())
+            .collect();
+        let mut compaction = match pick(self.compaction.as_ref(), &levels) {
+            Some(compaction) => compaction,
+            None => return Ok(false),
+        };
+        if self.table_options.compaction_filter.is_none() && compaction.is_trivial_move(&levels) {
+            compaction.kind = CompactionKind::Move;
+        }
+        let drop_tombstones = compaction.drops_tombstones(&levels);
+        debug!(
+            "{:?} of {} tables from level {} into level {}",
+            compaction.kind,
+            compaction.inputs.len(),
+            compaction.level,
+            compaction.output_level
+        );
+        // tables of the next level hold older data, so they go first
+        let mut inputs = Vec::new();
+        for level in compaction.input_levels() {
+            let (taken, kept): (Vec<SsTable>, Vec<SsTable>) = mem::take(&mut self.sstables[level])
+                .into_iter()
+                .partition(|table| compaction.is_input(table.id()));
+            self.sstables[level] = kept;
+            inputs.extend(taken);
+        }
+        let outputs = match compaction.kind {
+            CompactionKind::Merge => SsTable::merge_compact(
+                &mut inputs,
+                u8::try_from(compaction.output_level).unwrap(),
+                &self.table_options,
+                drop_tombstones,
+            ),
+            CompactionKind::Delete => Ok(Vec::new()),
+            CompactionKind::Move => {
+                self.move_tables(inputs, compaction.output_level)?;
+                return Ok(true);
+            }
+        };
+        match outputs {
+            Ok(outputs) => {
+                let output_level = &mut self.sstables[compaction.output_level];
+                output_level.extend(outputs);
+                output_level.sort();
+                for table in &inputs {
                     table.close()?;
                 }
-                self.sstables[i].clear();
+                Ok(true)
+            }
+            Err(err) => {
+                for table in inputs {
+                    self.sstables[table.properties().level as usize].push(table);
+                }
+                for level in &mut self.sstables {
+                    level.sort();
+                }
+                Err(err)
             }
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::path::PathBuf;
     use std::{env, fs, io};
 
     use rand::Rng;
     use serial_test::serial;
 
     use crate::config::Config;
-    use crate::LsmStorage;
+    use crate::{ByteString, CompactionStyle, LsmStorage};
 
     fn prepare_directories() -> String {
         let mut buf = env::temp_dir();
@@ -177,6 +339,23 @@ mod tests {
         base_dir.to_string()
     }
 
+    #[test]
+    #[serial]
+    fn storage_default_memtable_limit_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let mut storage = LsmStorage::load(Config {
+            base_path: base_dir.to_string(),
+            ..Config::default()
+        })?;
+        // the fixed memory of an empty memtable doesn't count against the limit
+        for i in 0..10 {
+            storage.insert(format!("k_{}", i).into_bytes(), b"value".to_vec())?;
+        }
+        assert!(storage.memtable.memory_usage() > 4096);
+        assert!(storage.table_properties().is_empty());
+        Ok(())
+    }
+
     #[test]
     #[serial]
     fn storage_insert_test() -> io::Result<()> {
@@ -184,8 +363,9 @@ mod tests {
 
         let config = Config {
             base_path: base_dir.to_string(),
-            memtable_limit_bytes: 4096,
+            memtable_limit_bytes: 32 * 1024,
             sstable_level_limit: 4,
+            ..Config::default()
         };
         let mut storage = LsmStorage::load(config)?;
         for i in 0..10000 {
@@ -202,6 +382,126 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[serial]
+    fn storage_removes_orphaned_files_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let mut level_path = PathBuf::from(&base_dir);
+        level_path.push("level-0");
+        fs::create_dir_all(&level_path)?;
+        fs::write(level_path.join("data_1.db"), b"partial")?;
+        fs::write(level_path.join("index_1.db.tmp"), b"partial")?;
+
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 4,
+            ..Config::default()
+        };
+        let mut storage = LsmStorage::load(config)?;
+        assert!(!level_path.join("data_1.db").exists());
+        assert!(!level_path.join("index_1.db.tmp").exists());
+        assert_eq!(None, storage.get(&"1".to_string().into_bytes())?);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn storage_leveled_compaction_test() -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 4,
+            level_size_base_bytes: 16 * 1024,
+            level_size_multiplier: 4,
+            target_file_size_bytes: 8 * 1024,
+            ..Config::default()
+        };
+        let mut storage = LsmStorage::load(config)?;
+        let mut hash_map = HashMap::new();
+        for _i in 0..30000 {
+            let key = format!("kt_{}", rng.gen::<u32>() % 5000).into_bytes();
+            let val = format!("vt_{}", rng.gen::<u32>()).into_bytes();
+            hash_map.insert(key.clone(), val.clone());
+            storage.insert(key, val)?;
+        }
+        for (key, val) in &hash_map {
+            assert_eq!(Some(val.clone()), storage.get(key)?);
+        }
+
+        let properties = storage.table_properties();
+        assert!(properties.iter().any(|table| table.level >= 2));
+        for level in 1..5 {
+            let mut tables: Vec<_> = properties.iter().filter(|t| t.level == level).collect();
+            tables.sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+            for pair in tables.windows(2) {
+                assert!(pair[0].largest_key < pair[1].smallest_key);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn storage_universal_compaction_test() -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 4,
+            compaction_strategy: CompactionStyle::Universal,
+            ..Config::default()
+        };
+        let mut storage = LsmStorage::load(config)?;
+        let mut hash_map = HashMap::new();
+        for _i in 0..30000 {
+            let key = format!("kt_{}", rng.gen::<u32>() % 5000).into_bytes();
+            let val = format!("vt_{}", rng.gen::<u32>()).into_bytes();
+            hash_map.insert(key.clone(), val.clone());
+            storage.insert(key, val)?;
+        }
+        for (key, val) in &hash_map {
+            assert_eq!(Some(val.clone()), storage.get(key)?);
+        }
+        let properties = storage.table_properties();
+        // tables written by one merge are a single sorted run
+        let runs: HashSet<_> = properties
+            .iter()
+            .map(|table| table.sequence_range)
+            .collect();
+        assert!(runs.len() < 4);
+        assert!(properties.iter().all(|table| table.level == 0));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn storage_universal_compaction_larger_than_target_files_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 256 * 1024,
+            sstable_level_limit: 4,
+            compaction_strategy: CompactionStyle::Universal,
+            ..Config::default()
+        };
+        let mut storage = LsmStorage::load(config)?;
+        // about 1 MiB, many times the 64 KiB target file size
+        for i in 0..10_000 {
+            let key = format!("k_{:05}", i).into_bytes();
+            storage.insert(key, vec![b'v'; 100])?;
+        }
+        for i in (0..10_000).step_by(97) {
+            let key = format!("k_{:05}", i).into_bytes();
+            assert_eq!(Some(vec![b'v'; 100]), storage.get(&key)?);
+        }
+        assert!(storage.table_properties().len() > 4);
+        Ok(())
+    }
+
     #[test]
     #[serial]
     fn storage_compact_test() -> io::Result<()> {
@@ -211,8 +511,9 @@ mod tests {
         let base_dir = prepare_directories();
         let config = Config {
             base_path: base_dir.to_string(),
-            memtable_limit_bytes: 4096,
+            memtable_limit_bytes: 32 * 1024,
             sstable_level_limit: 4,
+            ..Config::default()
         };
         let mut storage = LsmStorage::load(config)?;
         for _i in 0..100000 {
@@ -230,4 +531,78 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[serial]
+    fn storage_scan_test() -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+
+        let mut model = BTreeMap::new();
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            ..Config::default()
+        };
+        let mut storage = LsmStorage::load(config)?;
+        for _i in 0..20000 {
+            let key = format!("kt_{:03}", rng.gen::<u32>() % 500).into_bytes();
+            if rng.gen::<u32>() % 4 == 0 {
+                model.remove(&key);
+                storage.delete(&key)?;
+            } else {
+                let val = format!("vt_{}", rng.gen::<u32>()).into_bytes();
+                model.insert(key.clone(), val.clone());
+                storage.insert(key, val)?;
+            }
+        }
+        let entries: Vec<(ByteString, ByteString)> = storage
+            .scan(Some(b"kt_100"), Some(b"kt_300"))?
+            .into_iter()
+            .map(|kv| (kv.key_cloned(), kv.value_owned()))
+            .collect();
+        let expected: Vec<(ByteString, ByteString)> = model
+            .range(b"kt_100".to_vec()..=b"kt_300".to_vec())
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect();
+        assert_eq!(expected, entries);
+        assert_eq!(model.len(), storage.scan(None, None)?.len());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn storage_trivial_move_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = || Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            ..Config::default()
+        };
+        let mut storage = LsmStorage::load(config())?;
+        for i in 0..2000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes())?;
+        }
+        let mut flushed: Vec<u128> = storage.sstables[0].iter().map(|table| table.id()).collect();
+        flushed.sort();
+        assert!(flushed.len() > 1);
+
+        storage.compact_range(None, None, 2)?;
+        let mut ids: Vec<u128> = storage.table_properties().iter().map(|t| t.id).collect();
+        ids.sort();
+        assert_eq!(flushed, ids);
+        drop(storage);
+
+        let mut storage = LsmStorage::load(config())?;
+        let properties = storage.table_properties();
+        assert_eq!(flushed.len(), properties.len());
+        assert!(properties.iter().all(|table| table.level == 2));
+        for i in 0..2000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            assert_eq!(Some(format!("v_{}", i).into_bytes()), storage.get(&key)?);
+        }
+        Ok(())
+    }
 }
@@ -1,32 +1,32 @@
 extern crate probabilistic_collections;
 
 use std::cmp::Ordering;
-use std::io::Write;
+use std::io;
 use std::path::Path;
-use std::{fs, io};
-
-use crate::checksums::Checksums;
-use crate::datafile::{ReadOnlyDataFile, SizedFile, WriteableDataFile};
-use crate::memtable::{ByteString, MemTable};
-use crate::sstable_bloom_filter::SstableBloomFilter;
-use crate::sstable_index::SstableIndex;
-use crate::sstable_metadata::SsTableMetadata;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::block_cache::BlockCache;
+use crate::compaction;
+use crate::datafile::ReadOnlyDataFile;
+use crate::memtable::MemTable;
+use crate::merge_iterator::{InternalIterator, MergingIterator};
+use crate::rate_limiter::IoPriority;
+use crate::sstable_format::{
+    SplittingTableBuilder, SsTableBuilder, SsTableMeta, TableCursor, TableOptions, TableProperties,
+};
 use crate::{ByteStr, KeyValuePair};
 
-const INDEX_STEP: usize = 100;
-
-pub struct SsTable {
-    metadata: SsTableMetadata,
+pub(crate) struct SsTable {
+    meta: SsTableMeta,
     data: ReadOnlyDataFile,
-    index: SstableIndex,
-    bloom_filter: SstableBloomFilter,
-    size_bytes: u64,
 }
 
 impl Clone for SsTable {
     fn clone(&self) -> Self {
-        let metadata = self.metadata.clone();
-        SsTable::load(&metadata.metadata_path()).expect("Can't load sstable file")
+        SsTable::open(self.meta.reload().expect("Can't load sstable file"))
+            .expect("Can't load sstable file")
     }
 }
 
@@ -45,14 +45,11 @@ impl PartialOrd for SsTable {
 }
 
 impl Ord for SsTable {
+    /// Older data first, see `TableProperties::newest_sequence`.
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.eq(other) {
-            Ordering::Equal
-        } else if self.id() < other.id() {
-            Ordering::Less
-        } else {
-            Ordering::Greater
-        }
+        self.properties()
+            .newest_sequence()
+            .cmp(&other.properties().newest_sequence())
     }
 }
 
@@ -62,206 +59,148 @@ impl<'a> IntoIterator for &'a mut SsTable {
     type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        Iter {
-            table: self,
-            pos: 0,
-        }
+        self.iter_from(None)
     }
 }
 
 pub struct Iter<'a> {
     table: &'a mut SsTable,
-    pos: u64,
+    cursor: TableCursor,
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = KeyValuePair;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.table.data.read_record(self.pos) {
-            Ok(Some((kv_pair, len))) => {
-                self.pos += len;
-                Some(kv_pair)
-            }
-            Ok(None) => None,
+        match self.cursor.next(&self.table.data) {
+            Ok(kv_pair) => kv_pair,
             Err(err) => panic!("Unexpected error occurred. Err: {}", err),
         }
     }
 }
 
+impl InternalIterator for Iter<'_> {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        self.cursor.next(&self.table.data)
+    }
+}
+
 impl SsTable {
     pub fn id(&self) -> u128 {
-        self.metadata.id
+        self.meta.metadata.id
     }
 
-    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-        if !self.bloom_filter.contains(key) {
-            return Ok(None);
-        }
-
-        let res = match self.index.get(key) {
-            Some(pos) => {
-                let position = *pos;
-                self.data.read_record(position).map(|op| op.map(|p| p.0))
-            }
-            None => {
-                let (start, end) = self.index.position_range(key, self.size_bytes);
-                self.data.scan_range(key, start, end)
-            }
-        }?;
-        Ok(res.map(|kv| kv.value_owned()))
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<Bytes>> {
+        self.meta.get(&self.data, key)
     }
 }
 
 impl SsTable {
-    pub fn load(metadata_path: &Path) -> io::Result<SsTable> {
-        let metadata = SsTableMetadata::load(metadata_path);
-        Checksums::verify(&metadata)?;
-        let mut data_file =
-            ReadOnlyDataFile::open(&metadata.data_path()).expect("Can't create/open data file");
-        let index = SstableIndex::load(&metadata.index_path()).expect("Can't open index file");
-        let bloom_filter = SstableBloomFilter::load(&metadata.bloom_filter_path())
-            .expect("Cant open bloom filter file");
-        let size = data_file.size()?;
-        Ok(SsTable {
-            metadata,
-            data: data_file,
-            index,
-            bloom_filter,
-            size_bytes: size,
-        })
+    pub fn load(path: &Path, cache: Option<Arc<BlockCache>>) -> io::Result<SsTable> {
+        SsTable::open(SsTableMeta::load(path, cache)?)
     }
 
-    pub fn from_memtable(base_path: &str, memtable: &MemTable) -> io::Result<SsTable> {
-        let metadata = SsTableMetadata::new(base_path.to_string(), 0);
-        let (data_file, index, bloom_filter, size) = SsTable::write_data_file(&metadata, memtable)?;
-        index.write_to_file(&metadata.index_path())?;
-        Checksums::write_checksums(&metadata)?;
-        bloom_filter.write_to_file(&metadata.bloom_filter_path())?;
-        metadata.write_to_file()?;
+    fn open(meta: SsTableMeta) -> io::Result<SsTable> {
+        let data_file = ReadOnlyDataFile::open(&meta.metadata.data_path())?;
         Ok(SsTable {
-            metadata,
+            meta,
             data: data_file,
-            index,
-            bloom_filter,
-            size_bytes: size,
         })
     }
 
+    pub fn from_memtable(options: &TableOptions, memtable: &MemTable) -> io::Result<SsTable> {
+        let mut builder = SsTableBuilder::new(options, 0, memtable.size(), IoPriority::High)?;
+        for (key, val) in memtable.into_iter() {
+            builder.add(&key, &val)?;
+        }
+        SsTable::open(builder.finish()?)
+    }
+
+    /// Merges `tables`, oldest first, into new tables in `level`.
     pub fn merge_compact(
-        tables: &mut Vec<SsTable>,
+        tables: &mut [SsTable],
         level: u8,
-        base_path: &str,
-    ) -> io::Result<SsTable> {
-        let size: u64 = tables.iter().map(|table| table.size_bytes).sum();
-        let mut iterators = Vec::with_capacity(tables.len());
-        let mut values = Vec::with_capacity(tables.len());
-        for table in tables.iter_mut() {
-            let mut iterator = table.into_iter();
-            let value = iterator.next();
-            iterators.push(iterator);
-            values.push(value);
-        }
-        let metadata = SsTableMetadata::new(base_path.to_string(), level);
-        let mut file = WriteableDataFile::open(&metadata.data_path())?;
-        let mut index = SstableIndex::new();
-        let mut bloom_filter = SstableBloomFilter::new((size / 40) as usize);
-        let mut pos = 0u64;
-        let mut counter = 0usize;
-        loop {
-            let mut current_idx: Option<usize> = None;
-            for i in 0..iterators.len() {
-                if let Some(kv) = values[i].as_ref() {
-                    match current_idx {
-                        None => {
-                            current_idx = Some(i);
-                        }
-                        Some(curr) => {
-                            if kv.key_ref() <= values[curr].as_ref().unwrap().key_ref() {
-                                current_idx = Some(i);
-                            }
-                            if values[curr].as_ref().unwrap().key_ref() == kv.key_ref() {
-                                values[curr] = iterators[curr].next()
-                            }
-                        }
-                    }
-                }
-            }
-            match current_idx {
-                Some(idx) => {
-                    let kv = values[idx].as_ref().unwrap();
-                    if kv.value_ref() == vec![0] {
-                        continue;
-                    }
-                    let diff = file.write_key_value(kv.key_ref(), kv.value_ref())?;
-
-                    bloom_filter.insert(kv.key_ref());
-                    if counter % INDEX_STEP == 0 {
-                        index.insert(kv.key_cloned(), pos);
-                    }
-                    counter += 1;
-                    pos += diff;
-                    values[idx] = iterators[idx].next();
-                }
-                None => break,
-            }
+        options: &TableOptions,
+        drop_tombstones: bool,
+    ) -> io::Result<Vec<SsTable>> {
+        let size: u64 = tables
+            .iter()
+            .map(|table| table.meta.properties.data_size)
+            .sum();
+        let sequence_range = tables
+            .iter()
+            .filter_map(|table| table.meta.properties.sequence_range)
+            .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)));
+        let sources: Vec<Box<dyn InternalIterator>> = tables
+            .iter_mut()
+            .rev()
+            .map(|table| Box::new(table.iter_from(None)) as Box<dyn InternalIterator>)
+            .collect();
+        let mut input = MergingIterator::new(sources)?;
+        let expected_entries = size.min(options.target_file_size) / 40;
+        let mut builder =
+            SplittingTableBuilder::new(options, level, expected_entries as usize, sequence_range);
+        compaction::write_merged(
+            &mut input,
+            &mut builder,
+            level,
+            options,
+            None,
+            drop_tombstones,
+        )?;
+        builder.finish()?.into_iter().map(SsTable::open).collect()
+    }
+
+    /// Iterates over the entries with keys from `start` on.
+    pub fn iter_from(&mut self, start: Option<&ByteStr>) -> Iter<'_> {
+        Iter {
+            cursor: self.meta.cursor_from(start),
+            table: self,
         }
-        index.write_to_file(&metadata.index_path())?;
-        Checksums::write_checksums(&metadata)?;
-        bloom_filter.write_to_file(&metadata.bloom_filter_path())?;
-        metadata.write_to_file()?;
-        let size = file.size()?;
+    }
 
-        let data_file = ReadOnlyDataFile::open(&metadata.data_path())?;
+    pub fn properties(&self) -> &TableProperties {
+        &self.meta.properties
+    }
 
-        Ok(SsTable {
-            metadata,
-            data: data_file,
-            index,
-            bloom_filter,
-            size_bytes: size,
-        })
+    /// Moves the table to `level` without rewriting it.
+    pub fn move_to(&mut self, level: u8) -> io::Result<()> {
+        self.meta.move_to(level)
     }
 
-    pub fn close(&self) -> io::Result<()> {
-        fs::remove_file(self.metadata.metadata_path())?;
-        fs::remove_file(self.metadata.bloom_filter_path())?;
-        fs::remove_file(self.metadata.index_path())?;
-        fs::remove_file(self.metadata.checksum_path())?;
-        fs::remove_file(self.metadata.data_path())
-    }
-
-    fn write_data_file(
-        metadata: &SsTableMetadata,
-        memtable: &MemTable,
-    ) -> io::Result<(ReadOnlyDataFile, SstableIndex, SstableBloomFilter, u64)> {
-        let mut data_file = WriteableDataFile::open(&metadata.data_path())?;
-        let mut index = SstableIndex::new();
-        let mut bloom_filter = SstableBloomFilter::new(memtable.size());
-        let mut pos = 0;
-        for (i, (key, val)) in memtable.into_iter().enumerate() {
-            let diff = data_file.write_key_value(key, val)?;
-            bloom_filter.insert(key);
-            if i % INDEX_STEP == 0 {
-                index.insert(key.clone(), pos);
-            }
-            pos += diff;
-        }
-        data_file.flush()?;
+    pub fn verify_checksums(&self) -> io::Result<()> {
+        self.meta.verify_checksums()
+    }
 
-        let data_file = ReadOnlyDataFile::open(&metadata.data_path())?;
-        Ok((data_file, index, bloom_filter, pos))
+    pub fn close(&self) -> io::Result<()> {
+        self.meta.metadata.remove_files()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
     use std::{env, fs};
 
+    use bytes::Bytes;
     use serial_test::serial;
 
+    use crate::block_cache::BlockCache;
+    use crate::checksums::Checksums;
+    use crate::compaction::{CompactionFilter, FilterDecision};
+    use crate::compression::CompressionType;
+    use crate::config::Config;
+    use crate::datafile::WriteableDataFile;
     use crate::memtable::MemTable;
+    use crate::sstable_bloom_filter::SstableBloomFilter;
+    use crate::sstable_format::TableOptions;
+    use crate::sstable_index::{SstableIndex, TableIndex};
+    use crate::sstable_metadata::{SsTableMetadata, TableFormat};
     use crate::sync::sstable::SsTable;
+    use crate::KeyValuePair;
 
     fn prepare_directories() -> String {
         let mut buf = env::temp_dir();
@@ -277,16 +216,24 @@ mod tests {
         base_dir.to_string()
     }
 
+    fn table_options(base_dir: &str) -> TableOptions {
+        TableOptions::from_config(&Config {
+            base_path: base_dir.to_string(),
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
     #[test]
     #[serial]
     fn sstable_test() {
         let base_dir = prepare_directories();
-        let mut memtable = MemTable::new_in_memory_log();
+        let memtable = MemTable::new_in_memory_log();
         for i in 0..500 {
             let val = i * 100;
             memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
         }
-        let mut sstable = SsTable::from_memtable(&base_dir, &memtable).unwrap();
+        let mut sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
         check_values(&mut sstable);
         assert_eq!(None, sstable.get(&"1000".to_string().into_bytes()).unwrap());
     }
@@ -295,7 +242,7 @@ mod tests {
     #[serial]
     fn sstable_iterator_test() {
         let base_dir = prepare_directories();
-        let mut memtable = MemTable::new_in_memory_log();
+        let memtable = MemTable::new_in_memory_log();
         let mut entries = Vec::new();
         for i in 1..500 {
             let val = i * 100;
@@ -304,13 +251,11 @@ mod tests {
             entries.push((key.clone(), val.clone()));
             memtable.insert(key, val);
         }
-        let mut sstable = SsTable::from_memtable(&base_dir, &memtable).unwrap();
-        let mut i = 0;
+        let mut sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
         entries.sort_by(|a, b| a.0.cmp(&b.0));
-        for kv in sstable.into_iter() {
+        for (i, kv) in sstable.into_iter().enumerate() {
             assert_eq!(entries[i].0, kv.key_ref());
             assert_eq!(entries[i].1, kv.value_ref());
-            i += 1;
         }
     }
 
@@ -318,20 +263,291 @@ mod tests {
     #[serial]
     fn sstable_load_from_file_test() {
         let base_dir = prepare_directories();
-        let mut memtable = MemTable::new_in_memory_log();
+        let memtable = MemTable::new_in_memory_log();
         for i in 0..500 {
             let val = i * 100;
             memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
         }
-        let sstable = SsTable::from_memtable(&base_dir, &memtable).unwrap();
-        let mut sstable = SsTable::load(&sstable.metadata.metadata_path()).unwrap();
+        let sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
+        let mut sstable = SsTable::load(&sstable.meta.metadata.table_path(), None).unwrap();
         check_values(&mut sstable)
     }
 
+    /// Writes `memtable` in the five-file layout used before single-file tables.
+    fn write_legacy_table(base_dir: &str, memtable: &MemTable) -> PathBuf {
+        let metadata = SsTableMetadata {
+            base_path: base_dir.to_string(),
+            id: 1,
+            level: 0,
+            metadata_filename: "metadata_1.db".to_string(),
+            checksum_filename: "checksum_1.db".to_string(),
+            data_filename: "data_1.db".to_string(),
+            index_filename: "index_1.db".to_string(),
+            bloom_filter_filename: "bloom_1.db".to_string(),
+            format: TableFormat::Legacy,
+        };
+        let mut data_file = WriteableDataFile::create(&metadata.data_path(), None).unwrap();
+        let mut index = SstableIndex::new();
+        let mut bloom_filter = SstableBloomFilter::new(memtable.size());
+        let mut pos = 0;
+        for (i, (key, val)) in memtable.into_iter().enumerate() {
+            if i % 100 == 0 {
+                index.insert(key.clone(), pos);
+            }
+            bloom_filter.insert(&key);
+            pos += data_file.write_key_value(&key, &val).unwrap();
+        }
+        data_file.commit().unwrap();
+        fs::write(metadata.index_path(), index.to_bytes().unwrap()).unwrap();
+        fs::write(
+            metadata.bloom_filter_path(),
+            bloom_filter.to_bytes().unwrap(),
+        )
+        .unwrap();
+        Checksums::write_checksums(&metadata).unwrap();
+        serde_json::to_writer(File::create(metadata.metadata_path()).unwrap(), &metadata).unwrap();
+        metadata.metadata_path()
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_load_legacy_layout_test() {
+        let base_dir = prepare_directories();
+        let memtable = MemTable::new_in_memory_log();
+        for i in 0..500 {
+            let val = i * 100;
+            memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
+        }
+        let metadata_path = write_legacy_table(&base_dir, &memtable);
+        let mut sstable = SsTable::load(&metadata_path, None).unwrap();
+        check_values(&mut sstable);
+        assert_eq!(500, sstable.into_iter().count());
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_compressed_test() {
+        let base_dir = prepare_directories();
+        let memtable = MemTable::new_in_memory_log();
+        for i in 0..500 {
+            let val = i * 100;
+            memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
+        }
+        for codec in [
+            CompressionType::Lz4,
+            CompressionType::Snappy,
+            CompressionType::Zstd,
+        ] {
+            let options = TableOptions::from_config(&Config {
+                base_path: base_dir.clone(),
+                compression_per_level: vec![codec],
+                ..Config::default()
+            })
+            .unwrap();
+            let sstable = SsTable::from_memtable(&options, &memtable).unwrap();
+            let mut sstable = SsTable::load(&sstable.meta.metadata.table_path(), None).unwrap();
+            check_values(&mut sstable);
+            assert_eq!(500, sstable.into_iter().count());
+            sstable.close().unwrap();
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_properties_test() {
+        let base_dir = prepare_directories();
+        let memtable = MemTable::new_in_memory_log();
+        for i in 100..500 {
+            let val = i * 100;
+            memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
+        }
+        for i in 200..210 {
+            memtable.insert(i.to_string().into_bytes(), vec![0]);
+        }
+        let sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
+        let mut sstable = SsTable::load(&sstable.meta.metadata.table_path(), None).unwrap();
+        let properties = sstable.properties().clone();
+        assert_eq!(sstable.id(), properties.id);
+        assert_eq!(Some(b"100".to_vec()), properties.smallest_key);
+        assert_eq!(Some(b"499".to_vec()), properties.largest_key);
+        assert_eq!(Some(400), properties.entries);
+        assert_eq!(Some(10), properties.tombstones);
+        assert_eq!(
+            Some((sstable.id(), sstable.id())),
+            properties.sequence_range
+        );
+        assert_eq!(None, sstable.get(b"050").unwrap());
+        assert_eq!(None, sstable.get(b"5").unwrap());
+    }
+
+    struct SchemaFilter;
+
+    impl CompactionFilter for SchemaFilter {
+        fn filter(&self, _level: u8, _key: &[u8], value: &[u8]) -> FilterDecision {
+            if value.starts_with(b"deleted") {
+                FilterDecision::Remove
+            } else if let Some(rest) = value.strip_prefix(b"v1:") {
+                FilterDecision::ChangeValue([b"v2:", rest].concat())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_merge_with_compaction_filter_test() {
+        let base_dir = prepare_directories();
+        let older = MemTable::new_in_memory_log();
+        older.insert(b"a".to_vec(), b"v1:a".to_vec());
+        older.insert(b"b".to_vec(), b"v2:b".to_vec());
+        older.insert(b"c".to_vec(), b"v1:c".to_vec());
+        let newer = MemTable::new_in_memory_log();
+        newer.insert(b"c".to_vec(), b"deleted".to_vec());
+        let options = TableOptions {
+            compaction_filter: Some(Arc::new(SchemaFilter)),
+            ..table_options(&base_dir)
+        };
+        let mut tables = vec![
+            SsTable::from_memtable(&options, &older).unwrap(),
+            SsTable::from_memtable(&options, &newer).unwrap(),
+        ];
+        let mut merged = SsTable::merge_compact(&mut tables, 1, &options, false).unwrap();
+        assert_eq!(1, merged.len());
+        let merged = &mut merged[0];
+        assert_eq!(Some(Bytes::from_static(b"v2:a")), merged.get(b"a").unwrap());
+        assert_eq!(Some(Bytes::from_static(b"v2:b")), merged.get(b"b").unwrap());
+        assert_eq!(Some(Bytes::from_static(&[0])), merged.get(b"c").unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_merge_tombstones_test() {
+        let base_dir = prepare_directories();
+        let options = table_options(&base_dir);
+        let older = MemTable::new_in_memory_log();
+        older.insert(b"a".to_vec(), b"old".to_vec());
+        older.insert(b"b".to_vec(), b"old".to_vec());
+        let newer = MemTable::new_in_memory_log();
+        newer.insert(b"a".to_vec(), vec![0]);
+        newer.insert(b"b".to_vec(), b"new".to_vec());
+        newer.insert(b"c".to_vec(), vec![0]);
+        let mut tables = vec![
+            SsTable::from_memtable(&options, &older).unwrap(),
+            SsTable::from_memtable(&options, &newer).unwrap(),
+        ];
+
+        let mut kept = SsTable::merge_compact(&mut tables, 1, &options, false).unwrap();
+        let entries: Vec<KeyValuePair> = kept[0].iter_from(None).collect();
+        assert_eq!(
+            vec![
+                KeyValuePair::new(b"a".to_vec(), vec![0]),
+                KeyValuePair::new(b"b".to_vec(), b"new".to_vec()),
+                KeyValuePair::new(b"c".to_vec(), vec![0]),
+            ],
+            entries
+        );
+
+        let mut dropped = SsTable::merge_compact(&mut tables, 1, &options, true).unwrap();
+        let entries: Vec<KeyValuePair> = dropped[0].iter_from(None).collect();
+        assert_eq!(
+            vec![KeyValuePair::new(b"b".to_vec(), b"new".to_vec())],
+            entries
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_corrupted_block_test() {
+        let base_dir = prepare_directories();
+        let memtable = MemTable::new_in_memory_log();
+        for i in 0..500 {
+            let val = i * 100;
+            memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
+        }
+        let sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
+        let path = sstable.meta.metadata.table_path();
+        sstable.verify_checksums().unwrap();
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(10)).unwrap();
+        file.write_all(b"corrupted").unwrap();
+        drop(file);
+
+        // loading does not read data blocks, the damage shows up on access
+        let mut sstable = SsTable::load(&path, None).unwrap();
+        let err = sstable.get(&"0".to_string().into_bytes()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("at offset 0"));
+        assert!(sstable.verify_checksums().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_partitioned_index_test() {
+        let base_dir = prepare_directories();
+        let memtable = MemTable::new_in_memory_log();
+        for i in 0..100_000 {
+            let key = format!("key_{:06}", i).into_bytes();
+            memtable.insert(key, format!("value_{}", i).into_bytes());
+        }
+        let sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
+        assert!(matches!(&sstable.meta.index, TableIndex::Blocks(index) if index.is_partitioned()));
+
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let path = sstable.meta.metadata.table_path();
+        let mut sstable = SsTable::load(&path, Some(cache.clone())).unwrap();
+        for i in (0..100_000).step_by(997) {
+            let key = format!("key_{:06}", i).into_bytes();
+            let val = sstable.get(&key).unwrap();
+            assert_eq!(Some(Bytes::from(format!("value_{}", i))), val);
+        }
+        assert_eq!(None, sstable.get(b"key_0500000").unwrap());
+        assert_eq!(None, sstable.get(b"zzz").unwrap());
+        // index partitions are cached along with the data blocks
+        assert!(cache.hits() > 0);
+
+        let keys: Vec<_> = sstable
+            .iter_from(Some(b"key_049999a"))
+            .map(|kv| kv.key_cloned())
+            .collect();
+        assert_eq!(50_000, keys.len());
+        assert_eq!(b"key_050000".to_vec(), keys[0]);
+        sstable.verify_checksums().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn sstable_verify_checksums_skips_cache_test() {
+        let base_dir = prepare_directories();
+        let memtable = MemTable::new_in_memory_log();
+        for i in 0..500 {
+            let val = i * 100;
+            memtable.insert(i.to_string().into_bytes(), val.to_string().into_bytes());
+        }
+        let sstable = SsTable::from_memtable(&table_options(&base_dir), &memtable).unwrap();
+        let path = sstable.meta.metadata.table_path();
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let mut sstable = SsTable::load(&path, Some(cache.clone())).unwrap();
+        // caches the first block
+        sstable.get(&"0".to_string().into_bytes()).unwrap();
+        assert!(cache.usage() > 0);
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(10)).unwrap();
+        file.write_all(b"corrupted").unwrap();
+        drop(file);
+
+        // lookups still get the cached copy, the check reads the disk
+        assert!(sstable.get(&"0".to_string().into_bytes()).is_ok());
+        let err = sstable.verify_checksums().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
     fn check_values(sstable: &mut SsTable) {
         for i in 0..500 {
             let val = sstable.get(&i.to_string().into_bytes()).unwrap();
-            assert_eq!(true, val.is_some());
+            assert!(val.is_some());
             assert_eq!((i * 100).to_string().into_bytes(), val.unwrap());
         }
     }
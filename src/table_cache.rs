@@ -0,0 +1,156 @@
+//! Open data files of the tables of a `Db`, one handle per table shared by
+//! all its readers. Files are opened when a table is first read and kept
+//! open afterwards; once `Config::max_open_files` tables have an open file,
+//! the least recently used one is dropped from the cache before another is
+//! opened. A file dropped while a read still uses it is closed once that
+//! read is done.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::config::Config;
+use crate::datafile::ReadOnlyDataFile;
+
+pub(crate) struct TableCache {
+    max_open_files: usize,
+    /// Maps files into memory instead of reading them with system calls.
+    mmap: bool,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Files by table, with the tick they were last used at.
+    files: HashMap<u128, (u64, Arc<ReadOnlyDataFile>)>,
+    /// Tables by the tick their file was last used at, least recently used
+    /// first.
+    lru: BTreeMap<u64, u128>,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, table: u128) -> Option<Arc<ReadOnlyDataFile>> {
+        self.tick += 1;
+        let (tick, file) = self.files.get_mut(&table)?;
+        self.lru.remove(tick);
+        *tick = self.tick;
+        self.lru.insert(self.tick, table);
+        Some(file.clone())
+    }
+
+    fn remove(&mut self, table: u128) {
+        if let Some((tick, _)) = self.files.remove(&table) {
+            self.lru.remove(&tick);
+        }
+    }
+}
+
+impl TableCache {
+    pub(crate) fn new(max_open_files: usize, mmap: bool) -> TableCache {
+        TableCache {
+            max_open_files: max_open_files.max(1),
+            mmap,
+            state: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn from_config(config: &Config) -> TableCache {
+        TableCache::new(config.max_open_files, config.mmap_reads)
+    }
+
+    /// The data file of `table` at `path`, opened if it isn't yet.
+    pub(crate) fn get(&self, table: u128, path: &Path) -> io::Result<Arc<ReadOnlyDataFile>> {
+        if let Some(file) = self.state.lock().touch(table) {
+            return Ok(file);
+        }
+        let file = if self.mmap {
+            ReadOnlyDataFile::open_mmap(path)?
+        } else {
+            ReadOnlyDataFile::open(path)?
+        };
+        let file = Arc::new(file);
+        let mut state = self.state.lock();
+        // another reader may have opened it meanwhile
+        if let Some(file) = state.touch(table) {
+            return Ok(file);
+        }
+        while state.files.len() >= self.max_open_files {
+            match state.lru.first_key_value() {
+                Some((_, &oldest)) => state.remove(oldest),
+                None => break,
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.lru.insert(tick, table);
+        state.files.insert(table, (tick, file.clone()));
+        Ok(file)
+    }
+
+    /// Drops the file of a table that was removed.
+    pub(crate) fn evict(&self, table: u128) {
+        self.state.lock().remove(table);
+    }
+
+    /// Tables with a file in the cache.
+    pub(crate) fn open_files(&self) -> usize {
+        self.state.lock().files.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::{env, fs};
+
+    use crate::table_cache::TableCache;
+
+    #[test]
+    fn limits_open_files() {
+        let mut path = env::temp_dir();
+        path.push("table_cache_test");
+        fs::write(&path, b"data").unwrap();
+        let cache = TableCache::new(2, false);
+
+        // readers of a table share its file
+        let file = cache.get(1, &path).unwrap();
+        assert!(Arc::ptr_eq(&file, &cache.get(1, &path).unwrap()));
+        assert_eq!(1, cache.open_files());
+
+        cache.get(2, &path).unwrap();
+        cache.get(1, &path).unwrap();
+        // table 2 was used least recently
+        cache.get(3, &path).unwrap();
+        assert_eq!(2, cache.open_files());
+        assert!(Arc::ptr_eq(&file, &cache.get(1, &path).unwrap()));
+        // each evicts the table used least recently, 3 and then 1
+        cache.get(2, &path).unwrap();
+        cache.get(3, &path).unwrap();
+        // the evicted file stays open for its reader, others get a new one
+        assert!(!Arc::ptr_eq(&file, &cache.get(1, &path).unwrap()));
+        assert_eq!(b"data".to_vec(), file.read_block(0, 4).unwrap());
+
+        cache.evict(1);
+        assert_eq!(1, cache.open_files());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mmap_reads() {
+        let mut path = env::temp_dir();
+        path.push("table_cache_mmap_test");
+        fs::write(&path, b"mapped data").unwrap();
+        let cache = TableCache::new(2, true);
+        let file = cache.get(1, &path).unwrap();
+        assert_eq!(11, file.size().unwrap());
+        assert_eq!(b"data".to_vec(), file.read_block(7, 4).unwrap());
+        let err = file.read_block(8, 4).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,40 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use std::{fs, io, mem};
 
-use log::{debug, info};
+use bytes::Bytes;
+use log::{debug, info, warn};
 use parking_lot::lock_api::RwLockUpgradableReadGuard;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::Notify;
 
+use crate::compaction::{self, Compaction, CompactionKind, CompactionStrategy};
 use crate::config::Config;
 use crate::memtable::MemTable;
+use crate::merge_iterator::{self, InternalIterator, MergingIterator};
+use crate::sstable_format::{TableOptions, TableProperties};
+use crate::sstable_metadata::SsTableMetadata;
 use crate::tokio::sstable::SsTable;
+use crate::tokio::write_buffer_manager::{MemTableOwner, WriteBufferManager};
+use crate::tokio::write_controller::{LevelLoad, StallReason, WriteController, WriteStall};
 use crate::wal::CommandLog;
-use crate::{ByteStr, ByteString};
+use crate::{ByteStr, ByteString, KeyValuePair};
 
 const SSTABLE_MAX_LEVEL: usize = 5;
+/// Stopped writes check again after this long even if no flush or
+/// compaction finished.
+const STALL_RECHECK: Duration = Duration::from_millis(100);
+
+/// Number of locks writes to the same key are ordered by.
+const KEY_LOCK_STRIPES: usize = 64;
 
 #[derive(Clone)]
 pub struct Db {
@@ -23,10 +43,68 @@ pub struct Db {
 
 struct State {
     config: Config,
-    memtable: RwLock<MemTable>,
-    old_memtable: RwLock<Option<Arc<MemTable>>>,
+    table_options: TableOptions,
+    compaction: Box<dyn CompactionStrategy>,
+    /// The active memtable. Writers hold the read lock from their WAL
+    /// append until their memtable insert, so a memtable is only swapped out
+    /// once it has every write of its segment. The memtable itself deals with
+    /// concurrent access.
+    memtable: RwLock<Arc<MemTable>>,
+    /// Full memtables waiting to be flushed, oldest first.
+    immutables: RwLock<VecDeque<Arc<ImmutableMemTable>>>,
+    /// WAL segment of the active memtable. Writers only hold it for the
+    /// append.
     wal: RwLock<CommandLog<File>>,
+    /// Writes to the same key hold the same lock from their WAL append until
+    /// their memtable insert, so the memtable keeps the order of the WAL.
+    key_locks: Vec<Mutex<()>>,
+    next_wal_number: AtomicU64,
     levels: RwLock<SsLevelTable>,
+    write_controller: WriteController,
+    /// Notified whenever a flush or compaction finishes.
+    background_done: Notify,
+    /// Whether a compaction started by a write stall is running.
+    compaction_scheduled: AtomicBool,
+    /// Whether a flush of the immutable memtables is running.
+    flush_running: AtomicBool,
+    /// Set when a memtable was switched out before it was full; the next
+    /// flush doesn't wait for `Config::min_write_buffer_number_to_merge`.
+    force_flush: AtomicBool,
+    write_buffer_manager: Option<Arc<WriteBufferManager>>,
+}
+
+/// A full memtable and the WAL segment with its writes, removed once the
+/// memtable is in level 0.
+struct ImmutableMemTable {
+    memtable: Arc<MemTable>,
+    wal: CommandLog<File>,
+}
+
+/// Statistics of a `Db`.
+#[derive(Debug, Clone, Default)]
+pub struct DbStats {
+    /// Stall the next write would run into.
+    pub write_stall: WriteStall,
+    /// Sorted runs in level 0, see `CompactionStrategy::level0_runs`.
+    pub level0_runs: usize,
+    /// The compaction strategy's estimate, see
+    /// `Config::soft_pending_compaction_bytes_limit`.
+    pub pending_compaction_bytes: u64,
+    pub immutable_memtables: usize,
+    /// Memory of all memtables, see `MemTable::memory_usage`.
+    pub memtable_memory: usize,
+    /// Lookups that found their data block in the block cache or had to
+    /// read it, and the bytes the cached blocks take.
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    pub block_cache_usage: usize,
+    /// Table files open right now, see `Config::max_open_files`.
+    pub open_table_files: usize,
+    /// Writes that were delayed or stopped so far, and the time they spent
+    /// waiting in total.
+    pub delayed_writes: u64,
+    pub stopped_writes: u64,
+    pub stall_micros: u64,
 }
 
 struct SsLevelTable {
@@ -35,12 +113,14 @@ struct SsLevelTable {
 
 impl Db {
     pub fn load(config: Config) -> io::Result<Db> {
+        let table_options = TableOptions::from_config(&config)?;
         let path = PathBuf::from(&config.base_path);
         let mut levels = Vec::with_capacity(SSTABLE_MAX_LEVEL);
         for i in 0..SSTABLE_MAX_LEVEL {
             let mut level_path = path.clone();
             level_path.push(format!("level-{}", i));
             fs::create_dir_all(&level_path)?;
+            SsTableMetadata::remove_orphans(&level_path)?;
 
             let mut tables: Vec<SsTable> = Vec::new();
             let paths = fs::read_dir(level_path)?;
@@ -48,8 +128,8 @@ impl Db {
             for path in paths {
                 let path = path.expect("valid path in directory");
                 if let Some(name) = path.file_name().to_str() {
-                    if name.contains("metadata") {
-                        let sstable = SsTable::load(&path.path())?;
+                    if SsTableMetadata::is_table_file(name) {
+                        let sstable = SsTable::load(&path.path(), &table_options)?;
                         tables.push(sstable);
                     }
                 }
@@ -57,73 +137,116 @@ impl Db {
             tables.sort();
             levels.push(tables);
         }
-        let wal_path = Self::wal_path(&config.base_path);
-        let mut command_log = CommandLog::new(wal_path)?;
-        let memtable =
-            MemTable::from_log(&mut command_log).expect("Can't restore memtable from a log");
-        Ok(Db {
-            state: Arc::new(State {
-                config,
-                memtable: RwLock::new(memtable),
-                old_memtable: RwLock::new(None),
-                wal: RwLock::new(command_log),
-                levels: RwLock::new(SsLevelTable { levels }),
-            }),
-        })
+        // every segment left over holds a memtable that wasn't flushed yet
+        let mut immutables = VecDeque::new();
+        let mut next_wal_number = 1;
+        for (number, path) in Self::wal_segments(&config.base_path)? {
+            next_wal_number = number + 1;
+            let mut wal = CommandLog::new(path)?;
+            let memtable = MemTable::from_log(&mut wal, config.memtable_type)
+                .expect("Can't restore memtable from a log");
+            if memtable.size() == 0 {
+                wal.close()?;
+            } else {
+                immutables.push_back(Arc::new(ImmutableMemTable {
+                    memtable: Arc::new(memtable),
+                    wal,
+                }));
+            }
+        }
+        let wal = CommandLog::new(Self::wal_segment_path(&config.base_path, next_wal_number))?;
+        let write_buffer_manager = config.write_buffer_manager.clone().or_else(|| {
+            (config.db_write_buffer_size > 0)
+                .then(|| Arc::new(WriteBufferManager::new(config.db_write_buffer_size)))
+        });
+        let state = State {
+            table_options,
+            compaction: compaction::strategy_from_config(&config),
+            write_controller: WriteController::from_config(&config),
+            memtable: RwLock::new(Arc::new(MemTable::with_type(config.memtable_type))),
+            config,
+            immutables: RwLock::new(immutables),
+            wal: RwLock::new(wal),
+            key_locks: (0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            next_wal_number: AtomicU64::new(next_wal_number + 1),
+            levels: RwLock::new(SsLevelTable { levels }),
+            background_done: Notify::new(),
+            compaction_scheduled: AtomicBool::new(false),
+            flush_running: AtomicBool::new(false),
+            force_flush: AtomicBool::new(false),
+            write_buffer_manager,
+        };
+        // memtables recovered from the WAL go to level 0 before any write
+        if !state.immutables.read().is_empty() {
+            state.force_flush.store(true, Ordering::Release);
+            state.flush_immutables()?;
+        }
+        state.background_job_done();
+        let state = Arc::new(state);
+        if let Some(manager) = &state.write_buffer_manager {
+            let owner: Weak<dyn MemTableOwner> = Arc::downgrade(&state) as Weak<State>;
+            manager.register(owner);
+        }
+        Ok(Db { state })
     }
-    fn wal_path(base_path: &str) -> PathBuf {
+
+    fn wal_segment_path(base_path: &str, number: u64) -> PathBuf {
         let mut wal_path = PathBuf::from(base_path);
         wal_path.push("wal");
-        wal_path.push("wal.log");
+        wal_path.push(format!("wal_{}.log", number));
         wal_path
     }
+
+    /// WAL segments from oldest to newest. The single `wal.log` of older
+    /// versions comes first, as segment 0.
+    fn wal_segments(base_path: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+        let mut wal_dir = PathBuf::from(base_path);
+        wal_dir.push("wal");
+        fs::create_dir_all(&wal_dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&wal_dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let number = if name == "wal.log" {
+                Some(0)
+            } else {
+                name.strip_prefix("wal_")
+                    .and_then(|name| name.strip_suffix(".log"))
+                    .and_then(|number| number.parse().ok())
+            };
+            if let Some(number) = number {
+                segments.push((number, path));
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
     //TODO make it await wal insert
     pub async fn insert(&self, key: ByteString, value: ByteString) -> io::Result<()> {
+        self.wait_for_write_stall(key.len() + value.len()).await;
         {
-            let mut wal = self.state.wal.write();
-            wal.insert(&key, &value)?;
-        }
-        let mut old_clone = None;
-        {
-            let mut memtable = self.state.memtable.write();
+            let _key_lock = self.state.key_lock(&key).lock();
+            let memtable = self.state.memtable.read();
+            self.state.wal.write().insert(&key, &value)?;
             memtable.insert(key, value);
-            let size = memtable.size_in_bytes();
-            if size > self.state.config.memtable_limit_bytes {
-                let mut old = self.state.old_memtable.write();
-                if old.is_none() {
-                    let old_table = mem::replace(&mut *memtable, MemTable::new());
-                    let arc = Arc::new(old_table);
-                    old_clone = Some(arc.clone());
-                    *old = Some(arc);
-                }
-            }
-        }
-        if old_clone.is_some() {
-            let state = self.state.clone();
-            tokio::task::spawn_blocking(move || {
-                debug!("Memtable is too big, creating new sstable");
-                let sstable: SsTable =
-                    SsTable::from_memtable(&state.config.base_path, &old_clone.unwrap())
-                        .expect("Can't create new sstable");
-                {
-                    let mut levels = state.levels.write();
-                    levels.levels[0].push(sstable);
-                }
-                {
-                    let mut wal = state.wal.write();
-                    wal.close().expect("Can't remove old wal log");
-                    *wal = CommandLog::new(Self::wal_path(&state.config.base_path))
-                        .expect("Can't create WAL file");
-                }
-                {
-                    let mut old = state.old_memtable.write();
-                    *old = None
-                }
-            });
         }
+        self.state.rotate_memtable(false)?;
+        self.enforce_write_buffer();
         Ok(())
     }
 
+    /// Lets the write buffer manager, if any, flush a memtable to get back
+    /// under its budget.
+    fn enforce_write_buffer(&self) {
+        if let Some(manager) = &self.state.write_buffer_manager {
+            manager.enforce();
+        }
+    }
+
     #[inline]
     pub async fn update(&self, key: ByteString, value: ByteString) -> io::Result<()> {
         self.insert(key, value).await
@@ -131,20 +254,110 @@ impl Db {
 
     #[inline]
     pub async fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        self.wait_for_write_stall(key.len() + 1).await;
         {
-            let mut wal = self.state.wal.write();
-            wal.remove(key)?;
-        }
-        {
-            let mut memtable = self.state.memtable.write();
+            let _key_lock = self.state.key_lock(key).lock();
+            let memtable = self.state.memtable.read();
+            self.state.wal.write().remove(key)?;
             memtable.insert(key.to_vec(), vec![0]);
         }
+        self.state.rotate_memtable(false)?;
+        self.enforce_write_buffer();
         Ok(())
     }
+    /// Holds a write of `bytes` back while flushes or compactions are
+    /// behind, see `WriteStall`.
+    async fn wait_for_write_stall(&self, bytes: usize) {
+        let started = Instant::now();
+        let mut worst = WriteStall::None;
+        loop {
+            let background_done = self.state.background_done.notified();
+            match self.write_stall() {
+                WriteStall::None => break,
+                stall @ WriteStall::Delayed(_) => {
+                    if worst == WriteStall::None {
+                        worst = stall;
+                    }
+                    if let Some(delay) = self.state.write_controller.delay(bytes) {
+                        tokio::time::sleep(delay).await;
+                    }
+                    break;
+                }
+                stall @ WriteStall::Stopped(reason) => {
+                    debug!("Writes stopped: {:?}", reason);
+                    worst = stall;
+                    if reason == StallReason::ImmutableMemtables {
+                        self.state.schedule_flush();
+                    } else {
+                        self.schedule_compaction();
+                    }
+                    let _ = tokio::time::timeout(STALL_RECHECK, background_done).await;
+                }
+            }
+        }
+        self.state.write_controller.record(worst, started.elapsed());
+    }
+
+    fn write_stall(&self) -> WriteStall {
+        let immutable_memtables = self.state.immutables.read().len();
+        let memtable_full = self.state.memtable.read().entries_memory_usage()
+            > self.state.config.memtable_limit_bytes;
+        self.state
+            .write_controller
+            .stall(immutable_memtables, memtable_full)
+    }
+
+    /// Starts a compaction in the background unless one started this way is
+    /// still running.
+    fn schedule_compaction(&self) {
+        if self.state.compaction_scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let db = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = db.compact().await {
+                warn!("Compaction for stalled writes failed: {}", err);
+            }
+            db.state
+                .compaction_scheduled
+                .store(false, Ordering::Release);
+        });
+    }
+
+    pub fn stats(&self) -> DbStats {
+        let controller = &self.state.write_controller;
+        let levels = controller.level_load();
+        let block_cache = self.state.table_options.block_cache.as_ref();
+        // a read guard kept for the whole literal would deadlock with a
+        // flush waiting for the write lock once memtable_memory reads again
+        let immutable_memtables = self.state.immutables.read().len();
+        DbStats {
+            write_stall: self.write_stall(),
+            level0_runs: levels.level0_runs,
+            pending_compaction_bytes: levels.pending_compaction_bytes,
+            immutable_memtables,
+            memtable_memory: self.state.memtable_memory(),
+            block_cache_hits: block_cache.map_or(0, |cache| cache.hits()),
+            block_cache_misses: block_cache.map_or(0, |cache| cache.misses()),
+            block_cache_usage: block_cache.map_or(0, |cache| cache.usage()),
+            open_table_files: self.state.table_options.table_cache.open_files(),
+            delayed_writes: controller.delayed_writes(),
+            stopped_writes: controller.stopped_writes(),
+            stall_micros: controller.stall_micros(),
+        }
+    }
+
     pub async fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        Ok(self.get_pinned(key).await?.map(|val| val.to_vec()))
+    }
+
+    /// Like `get`, but without copying the value: it shares the memory of
+    /// the memtable, the cached block or the mapped file it was found in,
+    /// which stays alive while the value does.
+    pub async fn get_pinned(&self, key: &ByteStr) -> io::Result<Option<Bytes>> {
         match self.get_internal(key).await {
             Ok(Some(val)) => {
-                if val == vec![0] {
+                if val.as_ref() == [0] {
                     Ok(None)
                 } else {
                     Ok(Some(val))
@@ -153,22 +366,22 @@ impl Db {
             res => res,
         }
     }
-    async fn get_internal(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+    async fn get_internal(&self, key: &ByteStr) -> io::Result<Option<Bytes>> {
         // let key_owned = key.to_vec();
         {
-            let memtable = self.state.memtable.read();
-            let result = memtable.get(key);
+            let result = self.state.memtable.read().get_pinned(key);
             if let Some(val) = result {
                 debug!("Key: {:?} found in memtable", key);
-                return Ok(Some(val.to_owned()));
+                return Ok(Some(val));
             }
         }
         {
-            let old_memtable = self.state.old_memtable.read();
-            let result = old_memtable.as_ref().and_then(|m| m.get(key));
-            if let Some(val) = result {
-                debug!("Key: {:?} found in memtable", key);
-                return Ok(Some(val.to_owned()));
+            let immutables = self.state.immutables.read();
+            for immutable in immutables.iter().rev() {
+                if let Some(val) = immutable.memtable.get_pinned(key) {
+                    debug!("Key: {:?} found in immutable memtable", key);
+                    return Ok(Some(val));
+                }
             }
         }
         let state = self.state.clone();
@@ -188,55 +401,373 @@ impl Db {
         .await?
     }
 
+    /// Entries with keys from `start` to `end` (both inclusive, `None` for
+    /// unbounded), in key order.
+    pub async fn scan(
+        &self,
+        start: Option<&ByteStr>,
+        end: Option<&ByteStr>,
+    ) -> io::Result<Vec<KeyValuePair>> {
+        let start = start.map(|key| key.to_vec());
+        let end = end.map(|key| key.to_vec());
+        let memtable = self.state.memtable.read().clone();
+        let immutables: Vec<Arc<ImmutableMemTable>> =
+            self.state.immutables.read().iter().rev().cloned().collect();
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || {
+            let levels = state.levels.read();
+            let mut sources: Vec<Box<dyn InternalIterator>> =
+                vec![Box::new(memtable.iter_from(start.as_deref()))];
+            for immutable in &immutables {
+                sources.push(Box::new(immutable.memtable.iter_from(start.as_deref())));
+            }
+            for level in levels.levels.iter() {
+                let properties: Vec<&TableProperties> =
+                    level.iter().map(|table| table.properties()).collect();
+                let mut tables: Vec<Box<dyn InternalIterator>> = Vec::with_capacity(level.len());
+                for table in level {
+                    tables.push(Box::new(table.iter_from(start.as_deref())?));
+                }
+                sources.extend(merge_iterator::level_sources(
+                    merge_iterator::key_order(&properties),
+                    tables,
+                ));
+            }
+            let mut entries =
+                merge_iterator::collect_range(&mut MergingIterator::new(sources)?, end.as_deref())?;
+            entries.retain(|kv| kv.value_ref() != [0]);
+            Ok(entries)
+        })
+        .await?
+    }
+
+    /// Properties of every table, from level 0 down, oldest first within a
+    /// level.
+    pub fn table_properties(&self) -> Vec<TableProperties> {
+        let levels = self.state.levels.read();
+        levels
+            .levels
+            .iter()
+            .flatten()
+            .map(|sstable| sstable.properties().clone())
+            .collect()
+    }
+
+    /// Reads every table and checks it against its checksums. Blocks are
+    /// otherwise only checked when a lookup or scan reads them.
+    pub async fn verify_checksums(&self) -> io::Result<()> {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || {
+            let levels = state.levels.read();
+            for level in &levels.levels {
+                for sstable in level {
+                    sstable.verify_checksums()?;
+                }
+            }
+            Ok(())
+        })
+        .await?
+    }
+
     pub async fn compact(&self) -> io::Result<()> {
         let db = self.clone();
         tokio::task::spawn_blocking(move || {
             info!("Compaction started");
-            let mut new_levels: Vec<Vec<SsTable>> = Vec::new();
-            for _ in 0..SSTABLE_MAX_LEVEL {
-                new_levels.push(Vec::new());
-            }
+            while let Some(compaction) =
+                db.compact_once(|levels| db.state.compaction.pick(levels))?
             {
-                let levels = db.state.levels.upgradable_read();
-                for i in 0..SSTABLE_MAX_LEVEL - 1 {
-                    if levels.levels[i].len() >= db.state.config.sstable_level_limit {
-                        info!("Compaction on level {}", i);
-                        let new_sstable = SsTable::merge_compact(
-                            &levels.levels[i],
-                            u8::try_from(i + 1).unwrap(),
-                            &db.state.config.base_path,
-                        )?;
-                        new_levels[i + 1].push(new_sstable);
-                        // FIXME
-                        for table in &levels.levels[i] {
-                            table.close()?;
-                        }
-                    } else {
-                        for j in 0..levels.levels[i].len() {
-                            new_levels[i].push(levels.levels[i][j].clone());
-                        }
-                        // new_levels[i] = levels.levels[i].clone();
-                    }
-                }
-                let mut levels = RwLockUpgradableReadGuard::upgrade(levels);
-                *levels = SsLevelTable { levels: new_levels };
+                info!(
+                    "{:?} of {} tables from level {} into level {}",
+                    compaction.kind,
+                    compaction.inputs.len(),
+                    compaction.level,
+                    compaction.output_level
+                );
             }
             info!("Compaction finished");
             Ok(())
         })
         .await?
     }
+
+    /// Compacts the tables overlapping the key range from `start` to `end`
+    /// (both inclusive, `None` for unbounded) down into `target_level`. Each
+    /// level above it is merged into the next one in turn, and tables of
+    /// `target_level` and deeper are only rewritten where they overlap.
+    pub async fn compact_range(
+        &self,
+        start: Option<&ByteStr>,
+        end: Option<&ByteStr>,
+        target_level: usize,
+    ) -> io::Result<()> {
+        if target_level >= SSTABLE_MAX_LEVEL {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("there is no level {}", target_level),
+            ));
+        }
+        let db = self.clone();
+        let start = start.map(|key| key.to_vec());
+        let end = end.map(|key| key.to_vec());
+        tokio::task::spawn_blocking(move || {
+            for level in 0..target_level {
+                db.compact_once(|levels| {
+                    compaction::pick_range(levels, level, start.as_deref(), end.as_deref())
+                })?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Runs the compaction `pick` chooses, if any, and returns it.
+    fn compact_once<F>(&self, pick: F) -> io::Result<Option<Compaction>>
+    where
+        F: for<'a> FnOnce(&[Vec<&'a TableProperties>]) -> Option<Compaction>,
+    {
+        let levels = self.state.levels.upgradable_read();
+        let properties = levels.properties();
+        let mut compaction = match pick(&properties) {
+            Some(compaction) => compaction,
+            None => return Ok(None),
+        };
+        if self.state.table_options.compaction_filter.is_none()
+            && compaction.is_trivial_move(&properties)
+        {
+            compaction.kind = CompactionKind::Move;
+        }
+        let drop_tombstones = compaction.drops_tombstones(&properties);
+        drop(properties);
+        // tables of the next level hold older data, so they go first
+        let inputs: Vec<&SsTable> = compaction
+            .input_levels()
+            .iter()
+            .flat_map(|&level| levels.levels[level].iter())
+            .filter(|table| compaction.is_input(table.id()))
+            .collect();
+        let outputs = match compaction.kind {
+            CompactionKind::Merge => SsTable::merge_compact(
+                &inputs,
+                u8::try_from(compaction.output_level).unwrap(),
+                &self.state.table_options,
+                drop_tombstones,
+            )?,
+            CompactionKind::Delete => Vec::new(),
+            CompactionKind::Move => {
+                let mut levels = RwLockUpgradableReadGuard::upgrade(levels);
+                let moved = levels.move_tables(&compaction);
+                drop(levels);
+                self.state.background_job_done();
+                return moved.map(|()| Some(compaction));
+            }
+        };
+        let mut levels = RwLockUpgradableReadGuard::upgrade(levels);
+        let mut removed = Vec::new();
+        for level in compaction.input_levels() {
+            let (inputs, kept): (Vec<SsTable>, Vec<SsTable>) = mem::take(&mut levels.levels[level])
+                .into_iter()
+                .partition(|table| compaction.is_input(table.id()));
+            levels.levels[level] = kept;
+            removed.extend(inputs);
+        }
+        let output_level = &mut levels.levels[compaction.output_level];
+        output_level.extend(outputs);
+        output_level.sort();
+        drop(levels);
+        self.state.background_job_done();
+        for table in removed {
+            table.close()?;
+        }
+        Ok(Some(compaction))
+    }
+}
+
+impl State {
+    fn key_lock(&self, key: &ByteStr) -> &Mutex<()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.key_locks[hasher.finish() as usize % self.key_locks.len()]
+    }
+
+    /// Queues the active memtable for flushing, with the current WAL segment,
+    /// once it is full or `force` is set, and `Config::max_write_buffer_number`
+    /// leaves room.
+    fn rotate_memtable(self: &Arc<Self>, force: bool) -> io::Result<()> {
+        let state = self;
+        let limit = state.config.memtable_limit_bytes;
+        if !force && state.memtable.read().entries_memory_usage() <= limit {
+            return Ok(());
+        }
+        let mut memtable = state.memtable.write();
+        let full = memtable.entries_memory_usage() > limit;
+        if !(full || force && memtable.size() > 0) {
+            return Ok(());
+        }
+        let mut immutables = state.immutables.write();
+        if immutables.len() + 1 >= state.config.max_write_buffer_number.max(2) {
+            return Ok(());
+        }
+        let number = state.next_wal_number.fetch_add(1, Ordering::Relaxed);
+        let segment = CommandLog::new(Db::wal_segment_path(&state.config.base_path, number))?;
+        immutables.push_back(Arc::new(ImmutableMemTable {
+            memtable: mem::replace(
+                &mut *memtable,
+                Arc::new(MemTable::with_type(state.config.memtable_type)),
+            ),
+            wal: mem::replace(&mut *state.wal.write(), segment),
+        }));
+        if force {
+            state.force_flush.store(true, Ordering::Release);
+        }
+        let flush = force || immutables.len() >= state.min_flush_memtables();
+        drop(immutables);
+        drop(memtable);
+        if flush {
+            debug!("Memtable is too big, creating new sstable");
+            self.schedule_flush();
+        }
+        Ok(())
+    }
+
+    /// Flushes the immutable memtables in the background unless a flush is
+    /// running already.
+    fn schedule_flush(self: &Arc<Self>) {
+        if self.flush_running.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || loop {
+            let result = state.flush_immutables();
+            state.flush_running.store(false, Ordering::Release);
+            if let Err(err) = result {
+                warn!("Flush failed: {}", err);
+                break;
+            }
+            // memtables queued after the flush checked for them
+            let pending = state.force_flush.load(Ordering::Acquire)
+                || state.immutables.read().len() >= state.min_flush_memtables();
+            if !pending || state.flush_running.swap(true, Ordering::AcqRel) {
+                break;
+            }
+        });
+    }
+
+    /// Immutable memtables a flush waits for, see
+    /// `Config::min_write_buffer_number_to_merge`.
+    fn min_flush_memtables(&self) -> usize {
+        let max = self.config.max_write_buffer_number.max(2);
+        self.config
+            .min_write_buffer_number_to_merge
+            .clamp(1, max - 1)
+    }
+
+    /// Flushes the queued memtables, oldest first, until fewer than
+    /// `min_flush_memtables` are left. Each round merges all of them into
+    /// one table; their WAL segments go once it is in level 0.
+    fn flush_immutables(&self) -> io::Result<()> {
+        loop {
+            let batch: Vec<Arc<ImmutableMemTable>> =
+                self.immutables.read().iter().cloned().collect();
+            let min = if self.force_flush.swap(false, Ordering::AcqRel) {
+                1
+            } else {
+                self.min_flush_memtables()
+            };
+            if batch.is_empty() || batch.len() < min {
+                return Ok(());
+            }
+            let memtables: Vec<&MemTable> = batch.iter().map(|m| m.memtable.as_ref()).collect();
+            let sstable = SsTable::from_memtables(&self.table_options, &memtables)?;
+            self.levels.write().levels[0].push(sstable);
+            self.immutables.write().drain(..batch.len());
+            self.background_job_done();
+            for immutable in batch {
+                immutable.wal.close()?;
+            }
+        }
+    }
+
+    /// Updates the write stall state after a flush or compaction and wakes
+    /// the writes waiting for one.
+    fn background_job_done(&self) {
+        let load = {
+            let levels = self.levels.read();
+            let properties = levels.properties();
+            LevelLoad {
+                level0_runs: self.compaction.level0_runs(&properties[0]),
+                pending_compaction_bytes: self.compaction.pending_compaction_bytes(&properties),
+            }
+        };
+        self.write_controller.set_level_load(load);
+        self.background_done.notify_waiters();
+    }
+}
+
+impl MemTableOwner for State {
+    fn active_memory(&self) -> usize {
+        self.memtable.read().memory_usage()
+    }
+
+    fn memtable_memory(&self) -> usize {
+        let immutables: usize = self
+            .immutables
+            .read()
+            .iter()
+            .map(|immutable| immutable.memtable.memory_usage())
+            .sum();
+        self.active_memory() + immutables
+    }
+
+    fn flush_memtable(self: Arc<Self>) {
+        if let Err(err) = self.rotate_memtable(true) {
+            warn!("Switching memtables failed: {}", err);
+        }
+    }
+}
+
+impl SsLevelTable {
+    fn properties(&self) -> Vec<Vec<&TableProperties>> {
+        self.levels
+            .iter()
+            .map(|level| level.iter().map(|table| table.properties()).collect())
+            .collect()
+    }
+
+    /// Runs a `CompactionKind::Move`. A table that fails to move stays
+    /// where it was.
+    fn move_tables(&mut self, compaction: &Compaction) -> io::Result<()> {
+        let (moved, kept): (Vec<SsTable>, Vec<SsTable>) =
+            mem::take(&mut self.levels[compaction.level])
+                .into_iter()
+                .partition(|table| compaction.is_input(table.id()));
+        self.levels[compaction.level] = kept;
+        let mut result = Ok(());
+        for mut table in moved {
+            if let Err(err) = table.move_to(u8::try_from(compaction.output_level).unwrap()) {
+                result = result.and(Err(err));
+            }
+            self.levels[table.properties().level as usize].push(table);
+        }
+        self.levels[compaction.level].sort();
+        self.levels[compaction.output_level].sort();
+        result
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
     use std::{env, fs, io};
 
     use rand::Rng;
+    use serial_test::serial;
 
+    use crate::compaction::CompactionStyle;
     use crate::config::Config;
     use crate::tokio::db::Db;
+    use crate::WriteBufferManager;
 
     fn prepare_directories() -> String {
         let mut buf = env::temp_dir();
@@ -247,8 +778,17 @@ mod tests {
         base_dir.to_string()
     }
 
+    /// Waits until the memtables due for a flush are in level 0.
+    async fn wait_for_flush(storage: &Db) {
+        while storage.state.flush_running.load(Ordering::Acquire)
+            || storage.state.immutables.read().len() >= storage.state.min_flush_memtables()
+        {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
-    // #[serial]
+    #[serial]
     async fn storage_compact_test() -> io::Result<()> {
         let mut rng = rand::thread_rng();
 
@@ -256,8 +796,9 @@ mod tests {
         let base_dir = prepare_directories();
         let config = Config {
             base_path: base_dir.to_string(),
-            memtable_limit_bytes: 4096,
+            memtable_limit_bytes: 32 * 1024,
             sstable_level_limit: 4,
+            ..Config::default()
         };
         let storage = Db::load(config)?;
         let db_clone = storage.clone();
@@ -283,4 +824,525 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_compact_range_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            ..Config::default()
+        };
+        let storage = Db::load(config)?;
+        for i in 0..3000 {
+            let key = format!("k_{}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+            if i % 100 == 0 {
+                wait_for_flush(&storage).await;
+            }
+        }
+        wait_for_flush(&storage).await;
+        assert!(storage.table_properties().len() > 1);
+
+        storage.compact_range(None, None, 2).await?;
+        let properties = storage.table_properties();
+        assert!(!properties.is_empty());
+        assert!(properties.iter().all(|table| table.level == 2));
+        for i in 0..3000 {
+            let key = format!("k_{}", i).into_bytes();
+            let val = format!("v_{}", i).into_bytes();
+            assert_eq!(Some(val), storage.get(&key).await?);
+        }
+        assert!(storage.compact_range(None, None, 5).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_subcompactions_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            compaction_threads: 4,
+            ..Config::default()
+        };
+        let storage = Db::load(config)?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+            if i % 100 == 0 {
+                wait_for_flush(&storage).await;
+            }
+        }
+        wait_for_flush(&storage).await;
+
+        storage.compact_range(None, None, 1).await?;
+        let mut properties = storage.table_properties();
+        assert!(properties.len() > 1);
+        properties.sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+        for pair in properties.windows(2) {
+            assert!(pair[0].largest_key < pair[1].smallest_key);
+        }
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            let val = format!("v_{}", i).into_bytes();
+            assert_eq!(Some(val), storage.get(&key).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_scan_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            ..Config::default()
+        };
+        let storage = Db::load(config)?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+        }
+        for i in (0..3000).step_by(3) {
+            storage.delete(format!("k_{:04}", i).as_bytes()).await?;
+        }
+        storage.compact().await?;
+        for i in (0..3000).step_by(5) {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage
+                .insert(key, format!("v2_{}", i).into_bytes())
+                .await?;
+        }
+
+        let entries = storage.scan(Some(b"k_1000"), Some(b"k_1999")).await?;
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = (1000..2000)
+            .filter(|i| i % 3 != 0 || i % 5 == 0)
+            .map(|i| {
+                let val = if i % 5 == 0 {
+                    format!("v2_{}", i)
+                } else {
+                    format!("v_{}", i)
+                };
+                (format!("k_{:04}", i).into_bytes(), val.into_bytes())
+            })
+            .collect();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = entries
+            .into_iter()
+            .map(|kv| (kv.key_cloned(), kv.value_owned()))
+            .collect();
+        assert_eq!(expected, entries);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_reload_deletes_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = || Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            ..Config::default()
+        };
+        let storage = Db::load(config())?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+        }
+        for i in (0..3000).step_by(3) {
+            storage.delete(format!("k_{:04}", i).as_bytes()).await?;
+        }
+        storage.compact().await?;
+        // the latest deletes are only in the WAL
+        drop(storage);
+
+        let storage = Db::load(config())?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            let expected = (i % 3 != 0).then(|| format!("v_{}", i).into_bytes());
+            assert_eq!(expected, storage.get(&key).await?);
+        }
+        assert_eq!(2000, storage.scan(None, None).await?.len());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_mmap_reads_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let storage = Db::load(Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            mmap_reads: true,
+            ..Config::default()
+        })?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+        }
+        storage.compact().await?;
+        assert!(!storage.table_properties().is_empty());
+
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            assert_eq!(
+                Some(format!("v_{}", i).into_bytes()),
+                storage.get(&key).await?
+            );
+        }
+        assert_eq!(3000, storage.scan(None, None).await?.len());
+        storage.verify_checksums().await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_write_stall_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 4,
+            level0_slowdown_writes_trigger: 4,
+            level0_stop_writes_trigger: 6,
+            ..Config::default()
+        };
+        let storage = Db::load(config)?;
+        for i in 0..5000 {
+            let key = format!("k_{:04}", i % 1000).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+            // a write let through just before the stop can still queue one
+            // memtable
+            assert!(storage.stats().level0_runs <= 7);
+        }
+        let stats = storage.stats();
+        assert!(
+            stats.delayed_writes + stats.stopped_writes > 0,
+            "{:?}",
+            stats
+        );
+        for i in 4000..5000 {
+            let key = format!("k_{:04}", i % 1000).into_bytes();
+            assert_eq!(
+                Some(format!("v_{}", i).into_bytes()),
+                storage.get(&key).await?
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_universal_write_stall_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let storage = Db::load(Config {
+            base_path: base_dir.to_string(),
+            compaction_strategy: CompactionStyle::Universal,
+            ..Config::default()
+        })?;
+        // merges end up as many level 0 files, which mustn't stop writes
+        // while there are too few runs to compact
+        let writes = async {
+            for i in 0..6000 {
+                let key = format!("k_{:05}", i).into_bytes();
+                storage.insert(key, vec![b'v'; 1000]).await?;
+            }
+            io::Result::Ok(())
+        };
+        tokio::time::timeout(tokio::time::Duration::from_secs(60), writes)
+            .await
+            .expect("writes were stopped")?;
+        assert!(storage.table_properties().len() > storage.stats().level0_runs);
+        for i in (0..6000).step_by(100) {
+            let key = format!("k_{:05}", i).into_bytes();
+            assert_eq!(Some(vec![b'v'; 1000]), storage.get(&key).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_immutable_memtables_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = || Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            max_write_buffer_number: 4,
+            min_write_buffer_number_to_merge: 2,
+            ..Config::default()
+        };
+        let storage = Db::load(config())?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+            assert!(storage.stats().immutable_memtables < 4);
+        }
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            assert_eq!(
+                Some(format!("v_{}", i).into_bytes()),
+                storage.get(&key).await?
+            );
+        }
+        wait_for_flush(&storage).await;
+        // at least two memtables went into each table
+        let properties = storage.table_properties();
+        assert!(!properties.is_empty());
+        assert!(properties.iter().all(|table| table.entries > Some(400)));
+
+        // memtables that weren't flushed come back from their WAL segments
+        let tables = properties.len();
+        drop(storage);
+        let storage = Db::load(config())?;
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            assert_eq!(
+                Some(format!("v_{}", i).into_bytes()),
+                storage.get(&key).await?
+            );
+        }
+        // and get flushed without further writes
+        wait_for_flush(&storage).await;
+        assert_eq!(0, storage.stats().immutable_memtables);
+        assert!(storage.table_properties().len() > tables);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn storage_load_outside_runtime_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = || Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            ..Config::default()
+        };
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let storage = Db::load(config())?;
+            for i in 0..1000 {
+                let key = format!("k_{:04}", i).into_bytes();
+                storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+            }
+            wait_for_flush(&storage).await;
+            io::Result::Ok(())
+        })?;
+        drop(runtime);
+
+        // the memtables left in the WAL are flushed without a runtime
+        let storage = Db::load(config())?;
+        assert_eq!(0, storage.stats().immutable_memtables);
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            for i in 0..1000 {
+                let key = format!("k_{:04}", i).into_bytes();
+                assert_eq!(
+                    Some(format!("v_{}", i).into_bytes()),
+                    storage.get(&key).await?
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[serial]
+    async fn storage_concurrent_writers_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let config = || Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 16 * 1024,
+            sstable_level_limit: 100,
+            ..Config::default()
+        };
+        let storage = Db::load(config())?;
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    for i in 0..1000 {
+                        let key = format!("k_{:02}", i % 50).into_bytes();
+                        let value = format!("v_{}_{}", writer, i).into_bytes();
+                        if i % 7 == 0 {
+                            storage.delete(&key).await?;
+                        } else {
+                            storage.insert(key, value).await?;
+                        }
+                    }
+                    io::Result::Ok(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await??;
+        }
+        let mut values = Vec::new();
+        for i in 0..50 {
+            let key = format!("k_{:02}", i).into_bytes();
+            values.push(storage.get(&key).await?);
+        }
+
+        // replaying the WAL segments gives the same last writes
+        wait_for_flush(&storage).await;
+        drop(storage);
+        let storage = Db::load(config())?;
+        for (i, value) in values.into_iter().enumerate() {
+            let key = format!("k_{:02}", i).into_bytes();
+            assert_eq!(value, storage.get(&key).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_write_buffer_manager_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let manager = Arc::new(WriteBufferManager::new(64 * 1024));
+        let load = |name: &str| {
+            Db::load(Config {
+                base_path: format!("{}/{}", base_dir, name),
+                memtable_limit_bytes: 1024 * 1024,
+                sstable_level_limit: 100,
+                write_buffer_manager: Some(manager.clone()),
+                ..Config::default()
+            })
+        };
+        let (small, large) = (load("small")?, load("large")?);
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            large.insert(key, vec![b'v'; 100]).await?;
+            if i % 10 == 0 {
+                let key = format!("k_{:04}", i).into_bytes();
+                small.insert(key, vec![b'v'; 10]).await?;
+            }
+        }
+        wait_for_flush(&large).await;
+        wait_for_flush(&small).await;
+        // the memtable limit was never reached, the budget made them flush,
+        // mostly the one with the larger memtable
+        let flushes = |db: &Db| db.table_properties().len();
+        assert!(flushes(&large) > 1);
+        assert!(flushes(&small) < flushes(&large));
+        assert!(manager.memory_usage() <= 2 * manager.buffer_size());
+        for i in 0..3000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            assert_eq!(Some(vec![b'v'; 100]), large.get(&key).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_block_cache_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let storage = Db::load(Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            ..Config::default()
+        })?;
+        for i in 0..2000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+        }
+        wait_for_flush(&storage).await;
+        assert!(!storage.table_properties().is_empty());
+
+        let key = b"k_0001".to_vec();
+        assert_eq!(Some(b"v_1".to_vec()), storage.get(&key).await?);
+        let stats = storage.stats();
+        assert_eq!(0, stats.block_cache_hits);
+        assert_eq!(1, stats.block_cache_misses);
+        assert!(stats.block_cache_usage > 0);
+        assert_eq!(Some(b"v_1".to_vec()), storage.get(&key).await?);
+        let stats = storage.stats();
+        assert_eq!(1, stats.block_cache_hits);
+        assert_eq!(1, stats.block_cache_misses);
+
+        // scans use the cached blocks but don't add theirs
+        let usage = stats.block_cache_usage;
+        storage.scan(None, None).await?;
+        assert_eq!(usage, storage.stats().block_cache_usage);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_get_pinned_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let storage = Db::load(Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            ..Config::default()
+        })?;
+        for i in 0..2000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+        }
+        wait_for_flush(&storage).await;
+        storage.insert(b"new".to_vec(), b"value".to_vec()).await?;
+        storage.delete(b"k_0002").await?;
+
+        // values from the cached block and the memtable aren't copied
+        for key in [&b"k_0001"[..], b"new"] {
+            let first = storage.get_pinned(key).await?.unwrap();
+            let second = storage.get_pinned(key).await?.unwrap();
+            assert_eq!(first.as_ptr(), second.as_ptr());
+            assert_eq!(storage.get(key).await?, Some(first.to_vec()));
+        }
+        assert_eq!(None, storage.get_pinned(b"k_0002").await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn storage_max_open_files_test() -> io::Result<()> {
+        let base_dir = prepare_directories();
+        let storage = Db::load(Config {
+            base_path: base_dir.to_string(),
+            memtable_limit_bytes: 32 * 1024,
+            sstable_level_limit: 100,
+            block_cache_size_bytes: 0,
+            max_open_files: 4,
+            ..Config::default()
+        })?;
+        for i in 0..4000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            storage.insert(key, format!("v_{}", i).into_bytes()).await?;
+        }
+        wait_for_flush(&storage).await;
+        assert!(storage.table_properties().len() > 4);
+
+        for i in 0..4000 {
+            let key = format!("k_{:04}", i).into_bytes();
+            assert_eq!(
+                Some(format!("v_{}", i).into_bytes()),
+                storage.get(&key).await?
+            );
+            assert!(storage.stats().open_table_files <= 4);
+        }
+        // concurrent readers share the file of a table
+        let readers: Vec<_> = (0..16)
+            .map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let key = format!("k_{:04}", i).into_bytes();
+                    storage.get(&key).await
+                })
+            })
+            .collect();
+        for (i, reader) in readers.into_iter().enumerate() {
+            assert_eq!(Some(format!("v_{}", i).into_bytes()), reader.await??);
+        }
+        assert!(storage.stats().open_table_files <= 4);
+        Ok(())
+    }
 }
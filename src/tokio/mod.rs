@@ -1,3 +1,5 @@
 pub mod db;
 
 mod sstable;
+pub(crate) mod write_buffer_manager;
+pub(crate) mod write_controller;
@@ -1,216 +1,208 @@
 use std::cmp::Ordering;
-use std::collections::VecDeque;
-use std::io::Write;
 use std::path::Path;
-use std::{fs, io, mem};
+use std::sync::Arc;
+use std::{io, thread};
 
-use parking_lot::Mutex;
+use bytes::Bytes;
 
-use crate::checksums::Checksums;
-use crate::datafile::{ReadOnlyDataFile, SizedFile, WriteableDataFile};
+use crate::compaction;
+use crate::datafile::ReadOnlyDataFile;
 use crate::memtable::MemTable;
-use crate::sstable_bloom_filter::SstableBloomFilter;
-use crate::sstable_index::SstableIndex;
-use crate::sstable_metadata::SsTableMetadata;
-use crate::{ByteStr, ByteString, KeyValuePair};
-
-const INDEX_STEP: usize = 100;
-
-struct SsTableMeta {
-    metadata: SsTableMetadata,
-    index: SstableIndex,
-    bloom_filter: SstableBloomFilter,
-    size_bytes: u64,
-}
+use crate::merge_iterator::{InternalIterator, MergingIterator};
+use crate::rate_limiter::IoPriority;
+use crate::sstable_format::{
+    SplittingTableBuilder, SsTableBuilder, SsTableMeta, TableCursor, TableOptions, TableProperties,
+};
+use crate::table_cache::TableCache;
+use crate::{ByteStr, KeyValuePair};
 
 pub(crate) struct SsTable {
     meta: SsTableMeta,
-    data: Mutex<VecDeque<ReadOnlyDataFile>>,
+    /// Opens the data file when it's read.
+    files: Arc<TableCache>,
 }
 
 impl SsTable {
-    pub fn load(metadata_path: &Path) -> io::Result<SsTable> {
-        let metadata = SsTableMetadata::load(metadata_path);
-        Checksums::verify(&metadata)?;
-        let mut data_file =
-            ReadOnlyDataFile::open(&metadata.data_path()).expect("Can't create/open data file");
-        let index = SstableIndex::load(&metadata.index_path()).expect("Can't open index file");
-        let bloom_filter = SstableBloomFilter::load(&metadata.bloom_filter_path())
-            .expect("Can't open bloom filter file");
-        let size = data_file.size()?;
-        let mut queue = VecDeque::new();
-        //todo config
-        for _ in 0..8 {
-            queue.push_back(ReadOnlyDataFile::open(metadata.data_path().as_path()).unwrap());
-        }
-        Ok(SsTable {
-            meta: SsTableMeta {
-                metadata,
-                index,
-                bloom_filter,
-                size_bytes: size,
-            },
-            data: Mutex::new(queue),
-        })
+    pub fn load(path: &Path, options: &TableOptions) -> io::Result<SsTable> {
+        let meta = SsTableMeta::load(path, options.block_cache.clone())?;
+        Ok(SsTable::open(meta, options))
     }
 
-    pub fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-        if !self.meta.bloom_filter.contains(key) {
-            return Ok(None);
-        }
-        let mut data = None;
-        {
-            let mut locked_data = self.data.lock().pop_front();
-            mem::swap(&mut data, &mut locked_data);
-        }
-        // todo
-        let mut data = data.unwrap();
-        let result = match self.meta.index.get(key) {
-            Some(pos) => {
-                let position = *pos;
-                data.read_record(position).map(|op| op.map(|p| p.0))
-            }
-            None => {
-                let (start, end) = self.meta.index.position_range(key, self.meta.size_bytes);
-                data.scan_range(key, start, end)
-            }
-        }?;
-        {
-            self.data.lock().push_back(data);
+    fn open(meta: SsTableMeta, options: &TableOptions) -> SsTable {
+        SsTable {
+            meta,
+            files: options.table_cache.clone(),
         }
-        Ok(result.map(|kv| kv.value_owned()))
     }
 
-    pub fn from_memtable(base_path: &str, memtable: &MemTable) -> io::Result<SsTable> {
-        let metadata = SsTableMetadata::new(base_path.to_string(), 0);
-        let (_, index, bloom_filter, size) = SsTable::write_data_file(&metadata, memtable)?;
-        index.write_to_file(&metadata.index_path())?;
-        Checksums::write_checksums(&metadata)?;
-        bloom_filter.write_to_file(&metadata.bloom_filter_path())?;
-        metadata.write_to_file()?;
-        let mut queue = VecDeque::new();
-        //todo config
-        for _ in 0..8 {
-            queue.push_back(ReadOnlyDataFile::open(metadata.data_path().as_path()).unwrap());
-        }
-        let sstable_meta = SsTableMeta {
-            metadata,
-            index,
-            bloom_filter,
-            size_bytes: size,
-        };
+    fn data_file(&self) -> io::Result<Arc<ReadOnlyDataFile>> {
+        self.files.get(self.id(), &self.meta.metadata.data_path())
+    }
 
-        Ok(SsTable {
-            meta: sstable_meta,
-            data: Mutex::new(queue),
-        })
+    pub fn get(&self, key: &ByteStr) -> io::Result<Option<Bytes>> {
+        let data = self.data_file()?;
+        self.meta.get(&data, key)
     }
 
-    fn write_data_file(
-        metadata: &SsTableMetadata,
-        memtable: &MemTable,
-    ) -> io::Result<(ReadOnlyDataFile, SstableIndex, SstableBloomFilter, u64)> {
-        let mut data_file = WriteableDataFile::open(&metadata.data_path())?;
-        let mut index = SstableIndex::new();
-        let mut bloom_filter = SstableBloomFilter::new(memtable.size());
-        let mut pos = 0;
-        for (i, (key, val)) in memtable.into_iter().enumerate() {
-            let diff = data_file.write_key_value(key, val)?;
-            bloom_filter.insert(key);
-            if i % INDEX_STEP == 0 {
-                index.insert(key.clone(), pos);
-            }
-            pos += diff;
+    /// Flushes `memtables`, oldest first, into one level 0 table. A key in
+    /// several of them keeps the value of the newest.
+    pub fn from_memtables(options: &TableOptions, memtables: &[&MemTable]) -> io::Result<SsTable> {
+        let expected_entries = memtables.iter().map(|memtable| memtable.size()).sum();
+        let mut builder = SsTableBuilder::new(options, 0, expected_entries, IoPriority::High)?;
+        let sources: Vec<Box<dyn InternalIterator>> = memtables
+            .iter()
+            .rev()
+            .map(|memtable| Box::new(memtable.iter_from(None)) as Box<dyn InternalIterator>)
+            .collect();
+        let mut input = MergingIterator::new(sources)?;
+        while let Some(kv) = input.next_entry()? {
+            builder.add(kv.key_ref(), kv.value_ref())?;
         }
-        data_file.flush()?;
-
-        let data_file = ReadOnlyDataFile::open(&metadata.data_path())?;
-        Ok((data_file, index, bloom_filter, pos))
+        Ok(SsTable::open(builder.finish()?, options))
     }
 
     pub fn id(&self) -> u128 {
         self.meta.metadata.id
     }
 
-    pub fn merge_compact(tables: &Vec<SsTable>, level: u8, base_path: &str) -> io::Result<SsTable> {
-        let size: u64 = tables.iter().map(|table| table.meta.size_bytes).sum();
-        let mut iterators = Vec::with_capacity(tables.len());
-        let mut values = Vec::with_capacity(tables.len());
-        for table in tables.iter() {
-            let mut iterator = table.into_iter();
-            let value = iterator.next();
-            iterators.push(iterator);
-            values.push(value);
+    /// Merges `tables`, oldest first, into new tables in `level`. The key
+    /// space is split into up to `options.compaction_threads` ranges, each
+    /// merged on its own thread; the outputs are returned in key order.
+    pub fn merge_compact(
+        tables: &[&SsTable],
+        level: u8,
+        options: &TableOptions,
+        drop_tombstones: bool,
+    ) -> io::Result<Vec<SsTable>> {
+        let mut keys = Vec::new();
+        for table in tables {
+            keys.extend(table.meta.index.split_keys()?);
+        }
+        let split_points = compaction::split_points(
+            keys.iter().map(|key| key.as_slice()),
+            options.compaction_threads,
+        );
+        let mut ranges = Vec::with_capacity(split_points.len() + 1);
+        let mut start = None;
+        for point in split_points.iter() {
+            ranges.push((start, Some(point.as_slice())));
+            start = Some(point.as_slice());
+        }
+        ranges.push((start, None));
+        let merge = |start, end| {
+            SsTable::merge_range(
+                tables,
+                level,
+                options,
+                (start, end),
+                drop_tombstones,
+                ranges.len() as u64,
+            )
+        };
+        if ranges.len() == 1 {
+            return merge(None, None);
         }
-        let metadata = SsTableMetadata::new(base_path.to_string(), level);
-        let mut file = WriteableDataFile::open(&metadata.data_path())?;
-        let mut index = SstableIndex::new();
-        let mut bloom_filter = SstableBloomFilter::new((size / 40) as usize);
-        let mut pos = 0u64;
-        let mut counter = 0usize;
-        loop {
-            let mut current_idx: Option<usize> = None;
-            for i in 0..iterators.len() {
-                if let Some(kv) = values[i].as_ref() {
-                    match current_idx {
-                        None => {
-                            current_idx = Some(i);
-                        }
-                        Some(curr) => {
-                            if kv.key_ref() <= values[curr].as_ref().unwrap().key_ref() {
-                                current_idx = Some(i);
-                            }
-                            if values[curr].as_ref().unwrap().key_ref() == kv.key_ref() {
-                                values[curr] = iterators[curr].next()
-                            }
-                        }
-                    }
+        let results: Vec<io::Result<Vec<SsTable>>> = thread::scope(|scope| {
+            let handles: Vec<_> = ranges
+                .iter()
+                .map(|&(start, end)| scope.spawn(move || merge(start, end)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(io::Error::other("subcompaction thread panicked")))
+                })
+                .collect()
+        });
+        let mut outputs = Vec::new();
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(tables) => outputs.extend(tables),
+                Err(err) => {
+                    error.get_or_insert(err);
                 }
             }
-            match current_idx {
-                Some(idx) => {
-                    let kv = values[idx].as_ref().unwrap();
-                    if kv.value_ref() == vec![0] {
-                        continue;
-                    }
-                    let diff = file.write_key_value(kv.key_ref(), kv.value_ref())?;
-                    if counter % INDEX_STEP == 0 {
-                        index.insert(kv.key_cloned(), pos);
-                    }
-                    counter += 1;
-                    pos += diff;
-                    bloom_filter.insert(kv.key_ref());
-                    values[idx] = iterators[idx].next();
+        }
+        match error {
+            Some(err) => {
+                for table in outputs.iter() {
+                    table.close()?;
                 }
-                None => break,
+                Err(err)
             }
+            None => Ok(outputs),
         }
-        index.write_to_file(&metadata.index_path())?;
-        Checksums::write_checksums(&metadata)?;
-        bloom_filter.write_to_file(&metadata.bloom_filter_path())?;
-        metadata.write_to_file()?;
-        let size = file.size()?;
+    }
 
-        let mut queue = VecDeque::new();
-        for _ in 0..8 {
-            queue.push_back(ReadOnlyDataFile::open(metadata.data_path().as_path())?);
+    /// Merges the entries of `tables` with keys in `start..end`, one of
+    /// `parts` ranges of the same merge.
+    fn merge_range(
+        tables: &[&SsTable],
+        level: u8,
+        options: &TableOptions,
+        (start, end): (Option<&ByteStr>, Option<&ByteStr>),
+        drop_tombstones: bool,
+        parts: u64,
+    ) -> io::Result<Vec<SsTable>> {
+        let size: u64 = tables
+            .iter()
+            .map(|table| table.meta.properties.data_size)
+            .sum::<u64>()
+            / parts;
+        let sequence_range = tables
+            .iter()
+            .filter_map(|table| table.meta.properties.sequence_range)
+            .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)));
+        let mut sources: Vec<Box<dyn InternalIterator>> = Vec::with_capacity(tables.len());
+        for table in tables.iter().rev() {
+            sources.push(Box::new(table.iter_from(start)?));
         }
-        Ok(SsTable {
-            meta: SsTableMeta {
-                metadata,
-                index,
-                bloom_filter,
-                size_bytes: size,
-            },
-            data: Mutex::new(queue),
+        let mut input = MergingIterator::new(sources)?;
+        let expected_entries = size.min(options.target_file_size) / 40;
+        let mut builder =
+            SplittingTableBuilder::new(options, level, expected_entries as usize, sequence_range);
+        compaction::write_merged(
+            &mut input,
+            &mut builder,
+            level,
+            options,
+            end,
+            drop_tombstones,
+        )?;
+        Ok(builder
+            .finish()?
+            .into_iter()
+            .map(|meta| SsTable::open(meta, options))
+            .collect())
+    }
+
+    /// Iterates over the entries with keys from `start` on.
+    pub fn iter_from(&self, start: Option<&ByteStr>) -> io::Result<Iter> {
+        Ok(Iter {
+            data: self.data_file()?,
+            cursor: self.meta.cursor_from(start),
         })
     }
+
+    pub fn properties(&self) -> &TableProperties {
+        &self.meta.properties
+    }
+
+    /// Moves the table to `level` without rewriting it.
+    pub fn move_to(&mut self, level: u8) -> io::Result<()> {
+        self.meta.move_to(level)
+    }
+
+    pub fn verify_checksums(&self) -> io::Result<()> {
+        self.meta.verify_checksums()
+    }
+
     pub fn close(&self) -> io::Result<()> {
-        fs::remove_file(self.meta.metadata.metadata_path())?;
-        fs::remove_file(self.meta.metadata.bloom_filter_path())?;
-        fs::remove_file(self.meta.metadata.index_path())?;
-        fs::remove_file(self.meta.metadata.checksum_path())?;
-        fs::remove_file(self.meta.metadata.data_path())
+        self.files.evict(self.id());
+        self.meta.metadata.remove_files()
     }
 }
 
@@ -229,50 +221,51 @@ impl PartialOrd for SsTable {
 }
 
 impl Ord for SsTable {
+    /// Older data first, see `TableProperties::newest_sequence`.
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.eq(other) {
-            Ordering::Equal
-        } else if self.id() < other.id() {
-            Ordering::Less
-        } else {
-            Ordering::Greater
-        }
+        self.properties()
+            .newest_sequence()
+            .cmp(&other.properties().newest_sequence())
     }
 }
 
-impl<'a> IntoIterator for &'a SsTable {
+impl IntoIterator for &SsTable {
     type Item = KeyValuePair;
 
     type IntoIter = Iter;
 
     fn into_iter(self) -> Self::IntoIter {
-        let file = ReadOnlyDataFile::open(self.meta.metadata.data_path().as_path()).unwrap();
-        Iter { data: file, pos: 0 }
+        self.iter_from(None).unwrap()
     }
 }
 
 pub struct Iter {
-    data: ReadOnlyDataFile,
-    pos: u64,
+    data: Arc<ReadOnlyDataFile>,
+    cursor: TableCursor,
 }
 
 impl Iterator for Iter {
     type Item = KeyValuePair;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.data.read_record(self.pos) {
-            Ok(Some((kv_pair, len))) => {
-                self.pos += len;
-                Some(kv_pair)
-            }
-            Ok(None) => None,
+        match self.cursor.next(&self.data) {
+            Ok(kv_pair) => kv_pair,
             Err(err) => panic!("Unexpected error occurred. Err: {}", err),
         }
     }
 }
 
+impl InternalIterator for Iter {
+    fn next_entry(&mut self) -> io::Result<Option<KeyValuePair>> {
+        self.cursor.next(&self.data)
+    }
+}
+
 impl Clone for SsTable {
     fn clone(&self) -> Self {
-        SsTable::load(&self.meta.metadata.metadata_path()).expect("Can't load sstable file")
+        SsTable {
+            meta: self.meta.reload().expect("Can't load sstable file"),
+            files: self.files.clone(),
+        }
     }
 }
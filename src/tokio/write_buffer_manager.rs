@@ -0,0 +1,139 @@
+//! One memtable memory budget for several `Db`s in a process. Each write
+//! checks the memtables, and once they take too much of the budget the
+//! `Db` with the largest active memtable switches to a new one and flushes
+//! it. Memtables already waiting for a flush only count towards the total,
+//! since flushing more doesn't free them any sooner.
+
+use std::fmt;
+use std::sync::{Arc, Weak};
+
+use parking_lot::Mutex;
+
+/// A `Db` as seen by the `WriteBufferManager`.
+pub(crate) trait MemTableOwner: Send + Sync {
+    /// Memory of the active memtable.
+    fn active_memory(&self) -> usize;
+    /// Memory of all memtables, those waiting to be flushed included.
+    fn memtable_memory(&self) -> usize;
+    /// Switches to a new memtable and flushes the old one, if the owner has
+    /// room for another immutable memtable.
+    fn flush_memtable(self: Arc<Self>);
+}
+
+/// Share it between `Db`s through `Config::write_buffer_manager`.
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    owners: Mutex<Vec<Weak<dyn MemTableOwner>>>,
+}
+
+impl fmt::Debug for WriteBufferManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBufferManager")
+            .field("buffer_size", &self.buffer_size)
+            .finish()
+    }
+}
+
+impl WriteBufferManager {
+    pub fn new(buffer_size: usize) -> WriteBufferManager {
+        WriteBufferManager {
+            buffer_size,
+            owners: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Memory of the memtables of every `Db` using the manager.
+    pub fn memory_usage(&self) -> usize {
+        self.owners()
+            .iter()
+            .map(|owner| owner.memtable_memory())
+            .sum()
+    }
+
+    pub(crate) fn register(&self, owner: Weak<dyn MemTableOwner>) {
+        self.owners.lock().push(owner);
+    }
+
+    /// Flushes the largest active memtable if the active ones take 7/8 of
+    /// the budget, or the memtables are over budget and the active ones take
+    /// half of it.
+    pub(crate) fn enforce(&self) {
+        let owners = self.owners();
+        let active: usize = owners.iter().map(|owner| owner.active_memory()).sum();
+        let total: usize = owners.iter().map(|owner| owner.memtable_memory()).sum();
+        let over_budget = active > self.buffer_size / 8 * 7
+            || (total > self.buffer_size && active >= self.buffer_size / 2);
+        if !over_budget {
+            return;
+        }
+        if let Some(largest) = owners.into_iter().max_by_key(|owner| owner.active_memory()) {
+            largest.flush_memtable();
+        }
+    }
+
+    /// Owners still open; the closed ones are dropped from the list.
+    fn owners(&self) -> Vec<Arc<dyn MemTableOwner>> {
+        let mut owners = self.owners.lock();
+        owners.retain(|owner| owner.strong_count() > 0);
+        owners.iter().filter_map(|owner| owner.upgrade()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::tokio::write_buffer_manager::{MemTableOwner, WriteBufferManager};
+
+    struct Owner {
+        active: AtomicUsize,
+        immutable: AtomicUsize,
+    }
+
+    impl MemTableOwner for Owner {
+        fn active_memory(&self) -> usize {
+            self.active.load(Ordering::Relaxed)
+        }
+
+        fn memtable_memory(&self) -> usize {
+            self.active_memory() + self.immutable.load(Ordering::Relaxed)
+        }
+
+        fn flush_memtable(self: Arc<Self>) {
+            self.active.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn largest_memtable_is_flushed_first() {
+        let manager = WriteBufferManager::new(1000);
+        let owner = |active, immutable| {
+            Arc::new(Owner {
+                active: AtomicUsize::new(active),
+                immutable: AtomicUsize::new(immutable),
+            })
+        };
+        let (small, large) = (owner(300, 600), owner(500, 0));
+        let small_owner: Arc<dyn MemTableOwner> = small.clone();
+        let large_owner: Arc<dyn MemTableOwner> = large.clone();
+        manager.register(Arc::downgrade(&small_owner));
+        manager.register(Arc::downgrade(&large_owner));
+        assert_eq!(1400, manager.memory_usage());
+
+        manager.enforce();
+        assert_eq!(0, large.active_memory());
+        assert_eq!(300, small.active_memory());
+        // 900 bytes are within the budget
+        manager.enforce();
+        assert_eq!(300, small.active_memory());
+
+        drop(large_owner);
+        drop(large);
+        assert_eq!(900, manager.memory_usage());
+    }
+}
@@ -0,0 +1,218 @@
+//! Write stalls. `Db` delays or stops writes while flushes or compactions
+//! fall behind, so neither the memtables nor level 0 grow without bound.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::compaction::CompactionStyle;
+use crate::config::Config;
+
+/// Delays shorter than this are carried over to later writes instead of
+/// sleeping for them.
+const MIN_DELAY: Duration = Duration::from_millis(1);
+
+/// Why writes are stalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallReason {
+    Level0Runs,
+    PendingCompactionBytes,
+    ImmutableMemtables,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteStall {
+    #[default]
+    None,
+    /// Writes are held to `Config::delayed_write_rate_bytes_per_sec`.
+    Delayed(StallReason),
+    /// Writes wait until a flush or compaction finishes.
+    Stopped(StallReason),
+}
+
+/// Level 0 and compaction state the stall decision depends on, updated
+/// whenever a flush or compaction finishes.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct LevelLoad {
+    pub(crate) level0_runs: usize,
+    pub(crate) pending_compaction_bytes: u64,
+}
+
+pub(crate) struct WriteController {
+    level0_slowdown: usize,
+    level0_stop: usize,
+    soft_pending_bytes: u64,
+    hard_pending_bytes: u64,
+    max_write_buffers: usize,
+    delayed_write_rate: u64,
+    levels: Mutex<LevelLoad>,
+    /// Earliest time the next delayed write may go ahead.
+    next_write: Mutex<Instant>,
+    delayed_writes: AtomicU64,
+    stopped_writes: AtomicU64,
+    stall_micros: AtomicU64,
+}
+
+impl WriteController {
+    pub(crate) fn from_config(config: &Config) -> WriteController {
+        // level 0 only shrinks once it reaches the compaction trigger, and
+        // FIFO compaction never shrinks it by count
+        let level0_trigger = |trigger: usize| match (config.compaction_strategy, trigger) {
+            (CompactionStyle::Fifo, _) | (_, 0) => 0,
+            (_, trigger) => trigger.max(config.sstable_level_limit),
+        };
+        let level0_slowdown = level0_trigger(config.level0_slowdown_writes_trigger);
+        WriteController {
+            level0_slowdown,
+            level0_stop: level0_trigger(config.level0_stop_writes_trigger).max(level0_slowdown),
+            soft_pending_bytes: config.soft_pending_compaction_bytes_limit,
+            hard_pending_bytes: config.hard_pending_compaction_bytes_limit,
+            max_write_buffers: config.max_write_buffer_number.max(2),
+            delayed_write_rate: config.delayed_write_rate_bytes_per_sec.max(1),
+            levels: Mutex::new(LevelLoad::default()),
+            next_write: Mutex::new(Instant::now()),
+            delayed_writes: AtomicU64::new(0),
+            stopped_writes: AtomicU64::new(0),
+            stall_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn set_level_load(&self, load: LevelLoad) {
+        *self.levels.lock() = load;
+    }
+
+    pub(crate) fn level_load(&self) -> LevelLoad {
+        *self.levels.lock()
+    }
+
+    /// Stall for the next write, given the memtables waiting to be flushed
+    /// and whether the active one is full.
+    pub(crate) fn stall(&self, immutable_memtables: usize, memtable_full: bool) -> WriteStall {
+        let levels = self.level_load();
+        let reached = |value: u64, limit: u64| limit > 0 && value >= limit;
+        let level0_runs = levels.level0_runs as u64;
+        let pending_bytes = levels.pending_compaction_bytes;
+        if memtable_full && immutable_memtables + 1 >= self.max_write_buffers {
+            WriteStall::Stopped(StallReason::ImmutableMemtables)
+        } else if reached(level0_runs, self.level0_stop as u64) {
+            WriteStall::Stopped(StallReason::Level0Runs)
+        } else if reached(pending_bytes, self.hard_pending_bytes) {
+            WriteStall::Stopped(StallReason::PendingCompactionBytes)
+        } else if self.max_write_buffers > 3 && immutable_memtables + 2 >= self.max_write_buffers {
+            WriteStall::Delayed(StallReason::ImmutableMemtables)
+        } else if reached(level0_runs, self.level0_slowdown as u64) {
+            WriteStall::Delayed(StallReason::Level0Runs)
+        } else if reached(pending_bytes, self.soft_pending_bytes) {
+            WriteStall::Delayed(StallReason::PendingCompactionBytes)
+        } else {
+            WriteStall::None
+        }
+    }
+
+    /// How long a delayed write of `bytes` has to wait, if long enough to
+    /// sleep for. Delayed writes take turns, each pushing the next one back
+    /// by the time its bytes take at the delayed write rate.
+    pub(crate) fn delay(&self, bytes: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let mut next_write = self.next_write.lock();
+        let start = (*next_write).max(now);
+        let micros = bytes as u64 * 1_000_000 / self.delayed_write_rate;
+        *next_write = start + Duration::from_micros(micros);
+        let delay = start - now;
+        (delay >= MIN_DELAY).then_some(delay)
+    }
+
+    /// Counts a write that was held back by `stall` for `waited`.
+    pub(crate) fn record(&self, stall: WriteStall, waited: Duration) {
+        let counter = match stall {
+            WriteStall::None => return,
+            WriteStall::Delayed(_) => &self.delayed_writes,
+            WriteStall::Stopped(_) => &self.stopped_writes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.stall_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn delayed_writes(&self) -> u64 {
+        self.delayed_writes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn stopped_writes(&self) -> u64 {
+        self.stopped_writes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn stall_micros(&self) -> u64 {
+        self.stall_micros.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::Config;
+    use crate::tokio::write_controller::{LevelLoad, StallReason, WriteController, WriteStall};
+
+    #[test]
+    fn stalls_follow_thresholds() {
+        let controller = WriteController::from_config(&Config {
+            sstable_level_limit: 4,
+            level0_slowdown_writes_trigger: 2,
+            level0_stop_writes_trigger: 8,
+            soft_pending_compaction_bytes_limit: 1000,
+            hard_pending_compaction_bytes_limit: 0,
+            ..Config::default()
+        });
+        assert_eq!(WriteStall::None, controller.stall(0, true));
+        assert_eq!(
+            WriteStall::Stopped(StallReason::ImmutableMemtables),
+            controller.stall(1, true)
+        );
+        assert_eq!(WriteStall::None, controller.stall(1, false));
+
+        // the slowdown trigger is raised to the compaction trigger
+        controller.set_level_load(LevelLoad {
+            level0_runs: 3,
+            pending_compaction_bytes: 0,
+        });
+        assert_eq!(WriteStall::None, controller.stall(0, false));
+        controller.set_level_load(LevelLoad {
+            level0_runs: 4,
+            pending_compaction_bytes: 5000,
+        });
+        assert_eq!(
+            WriteStall::Delayed(StallReason::Level0Runs),
+            controller.stall(0, false)
+        );
+        controller.set_level_load(LevelLoad {
+            level0_runs: 8,
+            pending_compaction_bytes: 5000,
+        });
+        assert_eq!(
+            WriteStall::Stopped(StallReason::Level0Runs),
+            controller.stall(0, false)
+        );
+        controller.set_level_load(LevelLoad {
+            level0_runs: 0,
+            pending_compaction_bytes: 5000,
+        });
+        assert_eq!(
+            WriteStall::Delayed(StallReason::PendingCompactionBytes),
+            controller.stall(0, false)
+        );
+    }
+
+    #[test]
+    fn delays_add_up_to_the_write_rate() {
+        let controller = WriteController::from_config(&Config {
+            delayed_write_rate_bytes_per_sec: 1000,
+            ..Config::default()
+        });
+        assert_eq!(None, controller.delay(100));
+        let delay = controller.delay(100).unwrap();
+        assert!(delay > Duration::from_millis(90), "{:?}", delay);
+        assert!(delay <= Duration::from_millis(100), "{:?}", delay);
+    }
+}
@@ -89,7 +89,6 @@ impl CommandLog<File> {
         // let path = PathBuf::from("./wal.log");
         let mut new_file = OpenOptions::new()
             .read(true)
-            .write(true)
             .create(true)
             .append(true)
             .open(&path)?;